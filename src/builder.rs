@@ -1,24 +1,60 @@
 use crate::{
-    common::Error, expression::Expression, function::FunctionBuilder, module::ModuleBuilder,
-    program::Program,
+    common::Error,
+    expression::Expression,
+    function::{Function, FunctionBuilder},
+    module::ModuleBuilder,
+    program::{Opcode, Program},
 };
 
-pub struct Builder<'a> {
-    pub module_builder: ModuleBuilder<'a>,
+#[derive(Clone)]
+pub struct Builder {
+    pub module_builder: ModuleBuilder,
     pub function_builder: FunctionBuilder,
+    parents: Vec<FunctionBuilder>,
 }
 
-impl<'a> Builder<'a> {
-    pub fn new(source: &'a [u8]) -> Self {
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        let mut module_builder = ModuleBuilder::new();
+        // Function with index 0 is the module entry point.
+        module_builder.reserve_function(0);
         Self {
-            module_builder: ModuleBuilder::new(source),
+            module_builder,
             function_builder: FunctionBuilder::new(),
+            parents: Vec::new(),
         }
     }
 
+    pub fn enter_function(&mut self) {
+        let parent = std::mem::take(&mut self.function_builder);
+        self.parents.push(parent);
+    }
+
+    pub fn exit_function(&mut self) -> Function {
+        let parent = self.parents.pop().unwrap();
+        std::mem::replace(&mut self.function_builder, parent).build()
+    }
+
+    // Builds a program from the entry point code emitted so far, keeping the
+    // builder usable for the next chunk of code in the same module.
+    pub fn build_entry(&mut self) -> Program {
+        let function = self.function_builder.take_function();
+        let mut module_builder = self.module_builder.clone();
+        module_builder.set_function(0, function);
+        let mut program = Program::new();
+        program.push(module_builder.build());
+        program
+    }
+
     pub fn build(mut self) -> Program {
         let function = self.function_builder.build();
-        self.module_builder.push_function(function);
+        self.module_builder.set_function(0, function);
         let mut program = Program::new();
         program.push(self.module_builder.build());
         program
@@ -28,10 +64,41 @@ impl<'a> Builder<'a> {
 pub fn build(expression: &Expression, builder: &mut Builder) -> Result<(), Error> {
     match expression {
         Expression::Literal(literal) => literal.build(builder),
+        Expression::Group(group) => group.build(builder),
+        Expression::Unary(unary) => unary.build(builder),
         Expression::Binary(binary) => binary.build(builder),
         Expression::BinaryLogic(binary_logic) => binary_logic.build(builder),
         Expression::Variable(variable) => variable.build(builder),
         Expression::Assignment(assignment) => assignment.build(builder),
+        Expression::CompoundAssignment(assignment) => assignment.build(builder),
         Expression::ExprList(list) => list.build(builder),
+        Expression::Block(block) => block.build(builder),
+        Expression::FunctionDeclaration(declaration) => declaration.build(builder),
+        Expression::Call(call) => call.build(builder),
+        Expression::Return(ret) => ret.build(builder),
+        Expression::If(if_expression) => if_expression.build(builder),
+        Expression::Array(array) => array.build(builder),
+        Expression::Map(map) => map.build(builder),
+        Expression::Index(index) => index.build(builder),
+        Expression::IndexAssignment(assignment) => assignment.build(builder),
+        Expression::While(while_expression) => while_expression.build(builder),
+        Expression::Loop(loop_expression) => loop_expression.build(builder),
+        Expression::Break(break_expression) => break_expression.build(builder),
+        Expression::Continue(continue_expression) => continue_expression.build(builder),
+        Expression::Invalid(invalid) => invalid.build(builder),
+    }
+}
+
+pub fn build_statement(expression: &Expression, builder: &mut Builder) -> Result<(), Error> {
+    match expression {
+        Expression::ExprList(list) => list.build_statement(builder),
+        Expression::Block(block) => block.build_statement(builder),
+        Expression::Group(group) => group.build_statement(builder),
+        Expression::If(if_expression) => if_expression.build_statement(builder),
+        _ => {
+            build(expression, builder)?;
+            builder.function_builder.push(Opcode::Drop, 0..0);
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,521 @@
+use std::ops::Range;
+
+use crate::{
+    function::Function,
+    module::Module,
+    program::{Opcode, Program},
+    value::Value,
+};
+
+/*
+Layout of ".irisc" files, numbers are little endian, counts and operands are
+unsigned LEB128:
+
+    magic       "IRSC"
+    version     u16
+    checksum    u32, CRC-32 of everything after it
+    source      count, UTF-8 bytes
+    modules     count, then for every module:
+        constants   count, then tag byte and value for every constant
+        functions   count, then for every function:
+            params_count, frame_size
+            opcodes     count, then tag byte and operand if any
+            locations   start and end for every opcode
+*/
+
+pub const MAGIC: &[u8; 4] = b"IRSC";
+pub const VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 4;
+
+const BOOL_TAG: u8 = 0;
+const INTEGER_TAG: u8 = 1;
+const REAL_TAG: u8 = 2;
+const STRING_TAG: u8 = 3;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn opcode_tag(opcode: Opcode) -> (u8, Option<usize>) {
+    match opcode {
+        Opcode::Constant(index) => (0, Some(index)),
+        Opcode::Addict => (1, None),
+        Opcode::Subtract => (2, None),
+        Opcode::Multiply => (3, None),
+        Opcode::Divide => (4, None),
+        Opcode::Modulo => (5, None),
+        Opcode::And => (6, None),
+        Opcode::Or => (7, None),
+        Opcode::Xor => (8, None),
+        Opcode::Shl => (9, None),
+        Opcode::Shr => (10, None),
+        Opcode::Equal => (11, None),
+        Opcode::NotEqual => (12, None),
+        Opcode::Greater => (13, None),
+        Opcode::Less => (14, None),
+        Opcode::GreaterEqual => (15, None),
+        Opcode::LessEqual => (16, None),
+        Opcode::Negate => (17, None),
+        Opcode::Not => (18, None),
+        Opcode::BitNot => (19, None),
+        Opcode::JumpFalse(position) => (20, Some(position)),
+        Opcode::JumpTrue(position) => (21, Some(position)),
+        Opcode::Jump(position) => (22, Some(position)),
+        Opcode::StoreLocal(index) => (23, Some(index)),
+        Opcode::LoadLocal(index) => (24, Some(index)),
+        Opcode::Push => (25, None),
+        Opcode::Drop => (26, None),
+        Opcode::Call(index) => (27, Some(index)),
+        Opcode::Return => (28, None),
+        Opcode::MakeArray(count) => (29, Some(count)),
+        Opcode::MakeMap(count) => (30, Some(count)),
+        Opcode::GetIndex => (31, None),
+        Opcode::SetIndex => (32, None),
+    }
+}
+
+fn opcode_from_tag(tag: u8, reader: &mut Reader) -> Result<Opcode, String> {
+    Ok(match tag {
+        0 => Opcode::Constant(reader.usize()?),
+        1 => Opcode::Addict,
+        2 => Opcode::Subtract,
+        3 => Opcode::Multiply,
+        4 => Opcode::Divide,
+        5 => Opcode::Modulo,
+        6 => Opcode::And,
+        7 => Opcode::Or,
+        8 => Opcode::Xor,
+        9 => Opcode::Shl,
+        10 => Opcode::Shr,
+        11 => Opcode::Equal,
+        12 => Opcode::NotEqual,
+        13 => Opcode::Greater,
+        14 => Opcode::Less,
+        15 => Opcode::GreaterEqual,
+        16 => Opcode::LessEqual,
+        17 => Opcode::Negate,
+        18 => Opcode::Not,
+        19 => Opcode::BitNot,
+        20 => Opcode::JumpFalse(reader.usize()?),
+        21 => Opcode::JumpTrue(reader.usize()?),
+        22 => Opcode::Jump(reader.usize()?),
+        23 => Opcode::StoreLocal(reader.usize()?),
+        24 => Opcode::LoadLocal(reader.usize()?),
+        25 => Opcode::Push,
+        26 => Opcode::Drop,
+        27 => Opcode::Call(reader.usize()?),
+        28 => Opcode::Return,
+        29 => Opcode::MakeArray(reader.usize()?),
+        30 => Opcode::MakeMap(reader.usize()?),
+        31 => Opcode::GetIndex,
+        32 => Opcode::SetIndex,
+        _ => return Err(format!("Unknown opcode tag {}.", tag)),
+    })
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn usize(&mut self, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                break self.0.push(byte);
+            }
+            self.0.push(byte | 0x80);
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.usize(bytes.len());
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Bool(value) => {
+                self.u8(BOOL_TAG);
+                self.u8(*value as u8);
+            }
+            Value::Integer(value) => {
+                self.u8(INTEGER_TAG);
+                self.0.extend_from_slice(&value.to_le_bytes());
+            }
+            Value::Real(value) => {
+                self.u8(REAL_TAG);
+                self.0.extend_from_slice(&value.to_bits().to_le_bytes());
+            }
+            Value::String(value) => {
+                self.u8(STRING_TAG);
+                self.bytes(value.as_bytes());
+            }
+            // Constants are created from literals only.
+            Value::Array(_) | Value::Map(_) => {
+                unreachable!("{} can't be a constant.", value.type_name())
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.usize(function.params_count);
+        self.usize(function.frame_size);
+        self.usize(function.opcodes.len());
+        for &opcode in function.opcodes.iter() {
+            let (tag, operand) = opcode_tag(opcode);
+            self.u8(tag);
+            if let Some(operand) = operand {
+                self.usize(operand);
+            }
+        }
+        for location in function.locations.iter() {
+            self.usize(location.start);
+            self.usize(location.end);
+        }
+    }
+
+    fn module(&mut self, module: &Module) {
+        self.usize(module.constants.len());
+        for constant in module.constants.iter() {
+            self.value(constant);
+        }
+        self.usize(module.functions.len());
+        for function in module.functions.iter() {
+            self.function(function);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| "Unexpected end of file.".to_string())?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        let mut value = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            let bits = ((byte & 0x7F) as usize)
+                .checked_shl(shift)
+                .filter(|bits| bits >> shift == (byte & 0x7F) as usize)
+                .ok_or_else(|| "Number is too large.".to_string())?;
+            value |= bits;
+            if byte & 0x80 == 0 {
+                break Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let count = self.usize()?;
+        let bytes = self.take(count)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "String is not valid UTF-8.".to_string())
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.u8()? {
+            BOOL_TAG => match self.u8()? {
+                0 => Ok(Value::Bool(false)),
+                1 => Ok(Value::Bool(true)),
+                value => Err(format!("Invalid bool value {}.", value)),
+            },
+            INTEGER_TAG => Ok(Value::Integer(self.u64()? as i64)),
+            REAL_TAG => Ok(Value::Real(f64::from_bits(self.u64()?))),
+            STRING_TAG => Ok(Value::String(self.string()?.into())),
+            tag => Err(format!("Unknown constant tag {}.", tag)),
+        }
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        let params_count = self.usize()?;
+        let frame_size = self.usize()?;
+        let count = self.usize()?;
+        let mut opcodes = Vec::new();
+        for _ in 0..count {
+            let tag = self.u8()?;
+            opcodes.push(opcode_from_tag(tag, self)?);
+        }
+        let mut locations: Vec<Range<usize>> = Vec::new();
+        for _ in 0..count {
+            locations.push(self.usize()?..self.usize()?);
+        }
+        Ok(Function {
+            opcodes: opcodes.into_boxed_slice(),
+            locations: locations.into_boxed_slice(),
+            frame_size,
+            params_count,
+        })
+    }
+
+    fn module(&mut self) -> Result<Module, String> {
+        let mut constants = Vec::new();
+        for _ in 0..self.usize()? {
+            constants.push(self.value()?);
+        }
+        let mut functions = Vec::new();
+        for _ in 0..self.usize()? {
+            functions.push(self.function()?);
+        }
+        Ok(Module {
+            functions: functions.into_boxed_slice(),
+            constants: constants.into_boxed_slice(),
+        })
+    }
+}
+
+// Encodes the program with the source it was built from, so diagnostics of
+// the loaded program can still show code.
+pub fn save(program: &Program, source: &str) -> Vec<u8> {
+    let mut writer = Writer(Vec::new());
+    writer.bytes(source.as_bytes());
+    writer.usize(program.modules().len());
+    for module in program.modules() {
+        writer.module(module);
+    }
+
+    let mut result = Vec::with_capacity(HEADER_SIZE + writer.0.len());
+    result.extend_from_slice(MAGIC);
+    result.extend_from_slice(&VERSION.to_le_bytes());
+    result.extend_from_slice(&crc32(&writer.0).to_le_bytes());
+    result.extend_from_slice(&writer.0);
+    result
+}
+
+// Decodes a program and its source saved by "save".
+pub fn load(bytes: &[u8]) -> Result<(Program, String), String> {
+    if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+        return Err("Not an iris bytecode file.".to_string());
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {}, expected {}.",
+            version, VERSION
+        ));
+    }
+    let checksum = u32::from_le_bytes(bytes[6..HEADER_SIZE].try_into().unwrap());
+    let payload = &bytes[HEADER_SIZE..];
+    if crc32(payload) != checksum {
+        return Err("Checksum mismatch, the file is corrupted.".to_string());
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        position: 0,
+    };
+    let source = reader.string()?;
+    let mut program = Program::new();
+    for _ in 0..reader.usize()? {
+        program.push(reader.module()?);
+    }
+    if reader.position != payload.len() {
+        return Err("Unexpected data after the end of program.".to_string());
+    }
+    Ok((program, source))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn every_opcode() -> Vec<Opcode> {
+        vec![
+            Opcode::Constant(3),
+            Opcode::Addict,
+            Opcode::Subtract,
+            Opcode::Multiply,
+            Opcode::Divide,
+            Opcode::Modulo,
+            Opcode::And,
+            Opcode::Or,
+            Opcode::Xor,
+            Opcode::Shl,
+            Opcode::Shr,
+            Opcode::Equal,
+            Opcode::NotEqual,
+            Opcode::Greater,
+            Opcode::Less,
+            Opcode::GreaterEqual,
+            Opcode::LessEqual,
+            Opcode::Negate,
+            Opcode::Not,
+            Opcode::BitNot,
+            Opcode::JumpFalse(300),
+            Opcode::JumpTrue(0),
+            Opcode::Jump(usize::MAX),
+            Opcode::StoreLocal(128),
+            Opcode::LoadLocal(127),
+            Opcode::Push,
+            Opcode::Drop,
+            Opcode::Call(1),
+            Opcode::Return,
+            Opcode::MakeArray(2),
+            Opcode::MakeMap(70000),
+            Opcode::GetIndex,
+            Opcode::SetIndex,
+        ]
+    }
+
+    fn program() -> Program {
+        let opcodes = every_opcode();
+        let locations = (0..opcodes.len()).map(|i| i * 200..i * 200 + 3).collect();
+        let mut program = Program::new();
+        program.push(Module {
+            functions: vec![
+                Function {
+                    opcodes: opcodes.into(),
+                    locations,
+                    frame_size: 129,
+                    params_count: 0,
+                },
+                Function {
+                    opcodes: Box::new([]),
+                    locations: Box::new([]),
+                    frame_size: 2,
+                    params_count: 2,
+                },
+            ]
+            .into(),
+            constants: vec![
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::Integer(i64::MIN),
+                Value::Integer(-1),
+                Value::Real(-0.5),
+                Value::Real(f64::NAN),
+                Value::String("".into()),
+                Value::String("ünïcode".into()),
+            ]
+            .into(),
+        });
+        program.push(Module {
+            functions: Box::new([]),
+            constants: Box::new([]),
+        });
+        program
+    }
+
+    // Replaces the checksum after the payload was changed.
+    fn reseal(bytes: &mut [u8]) {
+        let checksum = crc32(&bytes[HEADER_SIZE..]);
+        bytes[6..HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    fn load_error(bytes: &[u8]) -> String {
+        match load(bytes) {
+            Ok(_) => panic!("expected load to fail"),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn every_opcode_tag_is_covered() {
+        let tags: HashSet<u8> = every_opcode()
+            .into_iter()
+            .map(|opcode| opcode_tag(opcode).0)
+            .collect();
+        assert_eq!(tags, (0..=32).collect());
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = program();
+        let (loaded, source) = load(&save(&program, "let x = 1\n")).unwrap();
+        assert_eq!(source, "let x = 1\n");
+        assert_eq!(loaded.modules().len(), program.modules().len());
+        for (loaded, module) in loaded.modules().iter().zip(program.modules()) {
+            assert_eq!(loaded.constants, module.constants);
+            assert_eq!(loaded.functions.len(), module.functions.len());
+            for (loaded, function) in loaded.functions.iter().zip(module.functions.iter()) {
+                assert_eq!(loaded.opcodes, function.opcodes);
+                assert_eq!(loaded.locations, function.locations);
+                assert_eq!(loaded.frame_size, function.frame_size);
+                assert_eq!(loaded.params_count, function.params_count);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = save(&program(), "");
+        bytes[0] = b'X';
+        assert_eq!(load_error(&bytes), "Not an iris bytecode file.");
+        assert_eq!(load_error(b"IRS"), "Not an iris bytecode file.");
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let mut bytes = save(&program(), "");
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(load_error(&bytes).starts_with("Unsupported bytecode version"));
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let mut bytes = save(&program(), "");
+        *bytes.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            load_error(&bytes),
+            "Checksum mismatch, the file is corrupted."
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut bytes = save(&program(), "");
+        bytes.push(0);
+        reseal(&mut bytes);
+        assert_eq!(
+            load_error(&bytes),
+            "Unexpected data after the end of program."
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_payload() {
+        let mut bytes = save(&program(), "");
+        bytes.pop();
+        reseal(&mut bytes);
+        assert_eq!(load_error(&bytes), "Unexpected end of file.");
+    }
+}
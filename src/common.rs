@@ -1,6 +1,31 @@
 use std::ops::Range;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // Code can't be parsed or built.
+    Compile,
+    // Operand of unsupported type, like adding bool to integer.
+    Type,
+    // Dividing by zero or shifting by unsupported amount.
+    Arithmetic,
+    // Array index out of bounds or missing map key.
+    Index,
+    // Opcode pops more values than the current function has on the stack.
+    StackUnderflow,
+    // Opcode refers to missing constant, local, function or code.
+    InvalidBytecode,
+    // Stack depth or call depth limit of the state is exceeded.
+    StackOverflow,
+    // Memory limit of the state is exceeded.
+    OutOfMemory,
+    // Instruction budget or deadline of the run ran out, the run can be
+    // resumed with a new budget.
+    BudgetExhausted,
+}
+
+#[derive(Debug)]
 pub struct Error {
     pub message: String,
     pub location: Range<usize>,
+    pub kind: ErrorKind,
 }
@@ -0,0 +1,102 @@
+use std::{
+    fmt::{Display, Formatter},
+    ops::Range,
+};
+
+use crate::common::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Label {
+    Parse,
+    Build,
+    Runtime,
+    Warning,
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Label::Parse => write!(f, "parse error"),
+            Label::Build => write!(f, "build error"),
+            Label::Runtime => write!(f, "runtime error"),
+            Label::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+pub struct Diagnostic {
+    pub label: Label,
+    pub message: String,
+    pub location: Range<usize>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(label: Label, error: Error) -> Self {
+        Self {
+            label,
+            message: error.message,
+            location: error.location,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    // Renders the diagnostic with the offending source line and "^^^" under
+    // the location, "name" is shown before the line and column numbers.
+    pub fn render(&self, source: &str, name: &str) -> String {
+        let mut result = format!("{}: {}\n", self.label, self.message);
+
+        let mut start = clamp_to_char(source, self.location.start);
+        if start == source.len() {
+            // Point the end of code right after the last written character.
+            start = source.trim_end().len();
+        }
+        let line_start = source[..start].rfind('\n').map_or(0, |index| index + 1);
+        let line_index = source[..line_start].matches('\n').count();
+        let line_end = source[line_start..]
+            .find('\n')
+            .map_or(source.len(), |end| line_start + end);
+        let line = source[line_start..line_end].trim_end_matches('\r');
+        let end = clamp_to_char(
+            source,
+            self.location.end.clamp(start, line_start + line.len()),
+        );
+
+        let line_number = (line_index + 1).to_string();
+        let column = source[line_start..start].chars().count() + 1;
+        let gutter = " ".repeat(line_number.len());
+
+        result.push_str(&format!(
+            "{}--> {}:{}:{}\n",
+            gutter, name, line_number, column
+        ));
+        result.push_str(&format!("{} |\n", gutter));
+        result.push_str(&format!("{} | {}\n", line_number, line));
+
+        // Keep tabs in the padding, so carets line up with the source above.
+        let padding: String = source[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source[start..end].chars().count().max(1);
+        result.push_str(&format!("{} | {}{}\n", gutter, padding, "^".repeat(width)));
+
+        for note in &self.notes {
+            result.push_str(&format!("{} = note: {}\n", gutter, note));
+        }
+        result
+    }
+}
+
+fn clamp_to_char(source: &str, mut offset: usize) -> usize {
+    offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
@@ -0,0 +1,170 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    function::Function,
+    module::Module,
+    program::{Opcode, Program},
+    value::Element,
+};
+
+// Jump targets of the function numbered in code order.
+fn collect_labels(function: &Function) -> BTreeMap<usize, usize> {
+    let mut labels: BTreeMap<usize, usize> = function
+        .opcodes
+        .iter()
+        .filter_map(|opcode| match *opcode {
+            Opcode::Jump(position) | Opcode::JumpFalse(position) | Opcode::JumpTrue(position) => {
+                Some((position, 0))
+            }
+            _ => None,
+        })
+        .collect();
+    for (number, label) in labels.values_mut().enumerate() {
+        *label = number;
+    }
+    labels
+}
+
+fn operand(opcode: Opcode, module: &Module, labels: &BTreeMap<usize, usize>) -> String {
+    match opcode {
+        Opcode::Constant(index) => match module.constants.get(index) {
+            Some(value) => format!("{} ({})", index, Element(value)),
+            None => format!("{} (missing)", index),
+        },
+        Opcode::Jump(position) | Opcode::JumpFalse(position) | Opcode::JumpTrue(position) => {
+            format!("L{}", labels[&position])
+        }
+        Opcode::StoreLocal(index)
+        | Opcode::LoadLocal(index)
+        | Opcode::Call(index)
+        | Opcode::MakeArray(index)
+        | Opcode::MakeMap(index) => index.to_string(),
+        _ => String::new(),
+    }
+}
+
+// Name of the opcode without its operand, like "JumpFalse".
+fn mnemonic(opcode: Opcode) -> String {
+    let name = format!("{:?}", opcode);
+    match name.find('(') {
+        Some(end) => name[..end].to_string(),
+        None => name,
+    }
+}
+
+// First line of the code at the location, empty for generated opcodes.
+fn source_text(source: &str, location: &std::ops::Range<usize>) -> String {
+    source
+        .get(location.clone())
+        .and_then(|text| text.lines().next())
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+// Lists the opcodes of the function with their offsets, jump targets as
+// "L<n>" labels, constants with their values and the source of every opcode.
+pub fn disassemble_function(module: &Module, index: usize, source: &str) -> String {
+    let function = &module.functions[index];
+    let labels = collect_labels(function);
+    let mut result = String::new();
+    writeln!(
+        result,
+        "fn {} (params: {}, frame size: {})",
+        index, function.params_count, function.frame_size
+    )
+    .unwrap();
+
+    for (position, &opcode) in function.opcodes.iter().enumerate() {
+        if let Some(label) = labels.get(&position) {
+            writeln!(result, "L{}:", label).unwrap();
+        }
+        let line = format!(
+            "{:>6}  {:<14}{:<20}",
+            position,
+            mnemonic(opcode),
+            operand(opcode, module, &labels)
+        );
+        let text = source_text(source, &function.locations[position]);
+        if text.is_empty() {
+            writeln!(result, "{}", line.trim_end()).unwrap();
+        } else {
+            writeln!(result, "{}; {}", line, text).unwrap();
+        }
+    }
+    if let Some(label) = labels.get(&function.opcodes.len()) {
+        writeln!(result, "L{}:", label).unwrap();
+    }
+    result
+}
+
+pub fn disassemble_module(module: &Module, source: &str) -> String {
+    (0..module.functions.len())
+        .map(|index| disassemble_function(module, index, source))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Disassembles all modules of the program, "source" is the code the program
+// was built from, so that locations can be shown as code.
+pub fn disassemble(program: &Program, source: &str) -> String {
+    program
+        .modules()
+        .iter()
+        .enumerate()
+        .map(|(index, module)| {
+            format!("module {}\n\n{}", index, disassemble_module(module, source))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn while_loop_listing() {
+        let code = "let i = 0; while i < 3 { i += 1 }; \"done\"";
+        let program = crate::testing::build(code);
+        let expected = concat!(
+            "module 0\n",
+            "\n",
+            "fn 0 (params: 0, frame size: 1)\n",
+            "     0  Constant      0 (0)               ; 0\n",
+            "     1  StoreLocal    0                   ; =\n",
+            "L0:\n",
+            "     2  LoadLocal     0                   ; i\n",
+            "     3  Constant      1 (3)               ; 3\n",
+            "     4  Less                              ; <\n",
+            "     5  JumpFalse     L1                  ; while\n",
+            "     6  LoadLocal     0                   ; +=\n",
+            "     7  Constant      2 (1)               ; 1\n",
+            "     8  Addict                            ; +=\n",
+            "     9  StoreLocal    0                   ; +=\n",
+            "    10  Jump          L0                  ; while\n",
+            "L1:\n",
+            "    11  Constant      3 (false)           ; while\n",
+            "    12  Drop\n",
+            "    13  Constant      4 (\"done\")          ; \"done\"\n",
+        );
+        assert_eq!(disassemble(&program, code), expected);
+    }
+
+    #[test]
+    fn functions_are_listed_with_their_index() {
+        let code = "fn add(a, b) {\n    a + b\n}\nadd(1, 2)";
+        let program = crate::testing::build(code);
+        let listing = disassemble(&program, code);
+        assert!(listing.contains("fn 1 (params: 2, frame size: 2)\n"));
+        assert!(listing.contains("Call          1                   ; add\n"));
+    }
+
+    #[test]
+    fn source_text_is_first_line_of_location() {
+        assert_eq!(source_text("a +\n  b", &(0..7)), "a +");
+        assert_eq!(source_text("  x  ", &(0..5)), "x");
+        // Generated opcodes may have locations outside the code.
+        assert_eq!(source_text("x", &(3..4)), "");
+    }
+}
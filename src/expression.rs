@@ -1,8 +1,8 @@
-use std::ops::Range;
+use std::{collections::HashSet, ops::Range};
 
 use crate::{
     builder::{self, Builder},
-    common::Error,
+    common::{Error, ErrorKind},
     program::Opcode,
     value::Value,
 };
@@ -21,7 +21,52 @@ fn build_constant(value: Value, location: Range<usize>, builder: &mut Builder) {
 
 impl Literal {
     pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
-        build_constant(self.value, self.location.clone(), builder);
+        build_constant(self.value.clone(), self.location.clone(), builder);
+        Ok(())
+    }
+}
+
+pub struct Group {
+    pub expr: Box<Expression>,
+    pub location: Range<usize>,
+}
+
+impl Group {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        builder::build(&self.expr, builder)
+    }
+
+    pub fn build_statement(&self, builder: &mut Builder) -> Result<(), Error> {
+        builder::build_statement(&self.expr, builder)
+    }
+}
+
+// Location of an operation widened to the groups among its operands, so
+// errors about an operand that is a group underline the whole group.
+pub fn operation_location(location: &Range<usize>, operands: &[&Expression]) -> Range<usize> {
+    operands
+        .iter()
+        .fold(location.clone(), |result, operand| match operand {
+            Expression::Group(group) => {
+                result.start.min(group.location.start)..result.end.max(group.location.end)
+            }
+            _ => result,
+        })
+}
+
+pub struct Unary {
+    pub expr: Box<Expression>,
+    pub opcode: Opcode,
+    pub location: Range<usize>,
+}
+
+impl Unary {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        builder::build(&self.expr, builder)?;
+        builder.function_builder.push(
+            self.opcode,
+            operation_location(&self.location, &[&self.expr]),
+        );
         Ok(())
     }
 }
@@ -36,10 +81,13 @@ pub struct Binary {
 impl Binary {
     pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
         builder::build(&self.lhs, builder)?;
+        builder.function_builder.add_pending(1);
         builder::build(&self.rhs, builder)?;
-        builder
-            .function_builder
-            .push(self.opcode, self.location.clone());
+        builder.function_builder.remove_pending(1);
+        builder.function_builder.push(
+            self.opcode,
+            operation_location(&self.location, &[&self.lhs, &self.rhs]),
+        );
         Ok(())
     }
 }
@@ -81,7 +129,10 @@ expr_end:
 impl BinaryLogic {
     pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
         builder::build(&self.lhs, builder)?;
-        let set_addr = builder.function_builder.push_unknown(self.location.clone());
+        // Only the left hand side is checked to be bool.
+        let set_addr = builder
+            .function_builder
+            .push_unknown(operation_location(&self.location, &[&self.lhs]));
         builder::build(&self.rhs, builder)?;
         let expr_end_addr = builder.function_builder.push_unknown(self.location.clone());
         builder.function_builder.set(
@@ -106,15 +157,18 @@ impl BinaryLogic {
     }
 }
 
-fn get_local(builder: &mut Builder, name: &String, location: &Range<usize>) -> Result<usize, Error> {
+fn get_local(
+    builder: &mut Builder,
+    name: &String,
+    location: &Range<usize>,
+) -> Result<usize, Error> {
     match builder.function_builder.get_local(name) {
         Some(index) => Ok(index),
-        None => {
-            return Err(Error {
-                message: format!("Can't find variable \"{}\".", name),
-                location: location.clone(),
-            })
-        }
+        None => Err(Error {
+            message: format!("Can't find variable \"{}\".", name),
+            location: location.clone(),
+            kind: ErrorKind::Compile,
+        }),
     }
 }
 
@@ -143,13 +197,55 @@ pub struct Assignment {
 
 impl Assignment {
     pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        // New variable is declared after its initializer is built, so the
+        // initializer sees the variable it shadows.
+        builder::build(&self.expr, builder)?;
         let position = if self.create_new_variable {
             builder.function_builder.new_local(&self.name)
         } else {
             get_local(builder, &self.name, &self.location)?
         };
+        builder
+            .function_builder
+            .push(Opcode::Push, self.location.clone());
+        builder
+            .function_builder
+            .push(Opcode::StoreLocal(position), self.location.clone());
 
+        Ok(())
+    }
+}
+
+/*
+a += b
+
+    LoadLocal a
+    {b}
+    Addict
+    Push
+    StoreLocal a
+*/
+
+pub struct CompoundAssignment {
+    pub name: String,
+    pub opcode: Opcode,
+    pub expr: Box<Expression>,
+    pub location: Range<usize>,
+}
+
+impl CompoundAssignment {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        let position = get_local(builder, &self.name, &self.location)?;
+
+        builder
+            .function_builder
+            .push(Opcode::LoadLocal(position), self.location.clone());
+        builder.function_builder.add_pending(1);
         builder::build(&self.expr, builder)?;
+        builder.function_builder.remove_pending(1);
+        builder
+            .function_builder
+            .push(self.opcode, self.location.clone());
         builder
             .function_builder
             .push(Opcode::Push, self.location.clone());
@@ -161,26 +257,739 @@ impl Assignment {
     }
 }
 
+pub struct Array {
+    pub elements: Vec<Expression>,
+    pub location: Range<usize>,
+}
+
+impl Array {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        for element in &self.elements {
+            builder::build(element, builder)?;
+            builder.function_builder.add_pending(1);
+        }
+        builder.function_builder.remove_pending(self.elements.len());
+        builder.function_builder.push(
+            Opcode::MakeArray(self.elements.len()),
+            self.location.clone(),
+        );
+        Ok(())
+    }
+}
+
+pub struct Map {
+    pub entries: Vec<(Expression, Expression)>,
+    pub location: Range<usize>,
+}
+
+impl Map {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        for (key, value) in &self.entries {
+            builder::build(key, builder)?;
+            builder.function_builder.add_pending(1);
+            builder::build(value, builder)?;
+            builder.function_builder.add_pending(1);
+        }
+        builder
+            .function_builder
+            .remove_pending(self.entries.len() * 2);
+        builder
+            .function_builder
+            .push(Opcode::MakeMap(self.entries.len()), self.location.clone());
+        Ok(())
+    }
+}
+
+pub struct Index {
+    pub container: Box<Expression>,
+    pub index: Box<Expression>,
+    pub location: Range<usize>,
+}
+
+impl Index {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        builder::build(&self.container, builder)?;
+        builder.function_builder.add_pending(1);
+        builder::build(&self.index, builder)?;
+        builder.function_builder.remove_pending(1);
+        builder
+            .function_builder
+            .push(Opcode::GetIndex, self.location.clone());
+        Ok(())
+    }
+}
+
+pub struct IndexAssignment {
+    pub container: Box<Expression>,
+    pub index: Box<Expression>,
+    pub expr: Box<Expression>,
+    pub location: Range<usize>,
+}
+
+impl IndexAssignment {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        builder::build(&self.container, builder)?;
+        builder.function_builder.add_pending(1);
+        builder::build(&self.index, builder)?;
+        builder.function_builder.add_pending(1);
+        builder::build(&self.expr, builder)?;
+        builder.function_builder.remove_pending(2);
+        builder
+            .function_builder
+            .push(Opcode::SetIndex, self.location.clone());
+        Ok(())
+    }
+}
+
 pub struct ExprList {
     pub exprs: Vec<Expression>,
+    // Set when the last expression is terminated by ";", its value is dropped.
+    pub discard_last: bool,
+}
+
+// Builds an expression of a list, functions the list declared up front are
+// only defined here.
+fn build_list_item(
+    expr: &Expression,
+    function_index: Option<usize>,
+    as_statement: bool,
+    builder: &mut Builder,
+) -> Result<(), Error> {
+    match (expr, function_index) {
+        (Expression::FunctionDeclaration(declaration), Some(index)) => {
+            declaration.define(index, builder)?;
+            if !as_statement {
+                build_constant(Value::Bool(false), declaration.location.clone(), builder);
+            }
+            Ok(())
+        }
+        _ if as_statement => builder::build_statement(expr, builder),
+        _ => builder::build(expr, builder),
+    }
 }
 
 impl ExprList {
+    // All functions of the list are declared before anything is built, so
+    // they can call each other regardless of order. A name declared twice
+    // would make calls before the second declaration use it, so it's an error.
+    fn declare_functions(&self, builder: &mut Builder) -> Result<Vec<Option<usize>>, Error> {
+        let mut names = HashSet::new();
+        for expr in &self.exprs {
+            if let Expression::FunctionDeclaration(declaration) = expr {
+                if !names.insert(declaration.name.as_str()) {
+                    return Err(Error {
+                        message: format!(
+                            "Function \"{}\" is already declared in this block.",
+                            declaration.name
+                        ),
+                        location: declaration.location.clone(),
+                        kind: ErrorKind::Compile,
+                    });
+                }
+            }
+        }
+        Ok(self
+            .exprs
+            .iter()
+            .map(|expr| match expr {
+                Expression::FunctionDeclaration(declaration) => Some(declaration.declare(builder)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        if self.exprs.is_empty() || self.discard_last {
+            self.build_statement(builder)?;
+            build_constant(Value::Bool(false), 0..0, builder);
+            return Ok(());
+        }
+        let function_indexes = self.declare_functions(builder)?;
+        let last = self.exprs.len() - 1;
+        for (i, (expr, function_index)) in self.exprs.iter().zip(function_indexes).enumerate() {
+            build_list_item(expr, function_index, i != last, builder)?;
+        }
+        Ok(())
+    }
+
+    pub fn build_statement(&self, builder: &mut Builder) -> Result<(), Error> {
+        let function_indexes = self.declare_functions(builder)?;
+        for (expr, function_index) in self.exprs.iter().zip(function_indexes) {
+            build_list_item(expr, function_index, true, builder)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Block {
+    pub list: ExprList,
+}
+
+impl Block {
     pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
-        for i in 0..(self.exprs.len() - 1) {
-            builder::build(&self.exprs[i], builder)?;
-            builder.function_builder.push(Opcode::Drop, 0..0);
+        builder.function_builder.enter_block();
+        let result = self.list.build(builder);
+        builder.function_builder.exit_block();
+        result
+    }
+
+    pub fn build_statement(&self, builder: &mut Builder) -> Result<(), Error> {
+        builder.function_builder.enter_block();
+        let result = self.list.build_statement(builder);
+        builder.function_builder.exit_block();
+        result
+    }
+}
+
+pub struct FunctionDeclaration {
+    pub name: String,
+    pub params: Vec<(String, Range<usize>)>,
+    pub body: Box<Expression>,
+    pub location: Range<usize>,
+}
+
+impl FunctionDeclaration {
+    // Emits the function into the builder entered for it.
+    fn build_function(&self, builder: &mut Builder) -> Result<(), Error> {
+        for (i, (param, location)) in self.params.iter().enumerate() {
+            if self.params[..i].iter().any(|(other, _)| other == param) {
+                return Err(Error {
+                    message: format!("Duplicate parameter \"{}\".", param),
+                    location: location.clone(),
+                    kind: ErrorKind::Compile,
+                });
+            }
+            builder.function_builder.new_param(param);
         }
-        builder::build(self.exprs.last().unwrap(), builder)?;
+        builder::build(&self.body, builder)?;
+        builder
+            .function_builder
+            .push(Opcode::Return, self.location.clone());
+        Ok(())
+    }
+
+    pub fn declare(&self, builder: &mut Builder) -> usize {
+        builder
+            .module_builder
+            .declare_function(&self.name, self.params.len())
+    }
+
+    // Builds the body of the function declared at "index".
+    pub fn define(&self, index: usize, builder: &mut Builder) -> Result<(), Error> {
+        // Leave the function on errors too, so the builder stays balanced.
+        builder.enter_function();
+        let result = self.build_function(builder);
+        let function = builder.exit_function();
+        result?;
+        builder.module_builder.set_function(index, function);
+        Ok(())
+    }
+
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        let index = self.declare(builder);
+        self.define(index, builder)?;
+        build_constant(Value::Bool(false), self.location.clone(), builder);
         Ok(())
     }
 }
 
+pub struct Call {
+    pub name: String,
+    pub args: Vec<Expression>,
+    pub location: Range<usize>,
+}
+
+impl Call {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        let index = match builder.module_builder.get_function(&self.name) {
+            Some(index) => index,
+            None => {
+                return Err(Error {
+                    message: format!("Can't find function \"{}\".", self.name),
+                    location: self.location.clone(),
+                    kind: ErrorKind::Compile,
+                })
+            }
+        };
+
+        let params_count = builder.module_builder.params_count(index);
+        if params_count != self.args.len() {
+            return Err(Error {
+                message: format!(
+                    "Function \"{}\" takes {} argument(s), but {} given.",
+                    self.name,
+                    params_count,
+                    self.args.len()
+                ),
+                location: self.location.clone(),
+                kind: ErrorKind::Compile,
+            });
+        }
+
+        for arg in &self.args {
+            builder::build(arg, builder)?;
+            builder.function_builder.add_pending(1);
+        }
+        builder.function_builder.remove_pending(self.args.len());
+        builder
+            .function_builder
+            .push(Opcode::Call(index), self.location.clone());
+        Ok(())
+    }
+}
+
+pub struct Return {
+    pub expr: Option<Box<Expression>>,
+    pub location: Range<usize>,
+}
+
+impl Return {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        match &self.expr {
+            Some(expr) => builder::build(expr, builder)?,
+            None => build_constant(Value::Bool(false), self.location.clone(), builder),
+        }
+        builder
+            .function_builder
+            .push(Opcode::Return, self.location.clone());
+        Ok(())
+    }
+}
+
+pub struct If {
+    pub condition: Box<Expression>,
+    pub then_branch: Box<Expression>,
+    pub else_branch: Option<Box<Expression>>,
+    pub location: Range<usize>,
+}
+
+/*
+if a { b } else { c }
+
+    {a}
+    JumpFalse else_branch
+    {b}
+    Jump if_end
+else_branch:
+    {c}
+if_end:
+
+Without "else" (only as statement):
+
+    {a}
+    JumpFalse if_end
+    {b}
+if_end:
+*/
+
+impl If {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        let else_branch = match &self.else_branch {
+            Some(else_branch) => else_branch,
+            None => {
+                return Err(Error {
+                    message: "\"if\" without \"else\" can't be used as a value.".to_string(),
+                    location: self.location.clone(),
+                    kind: ErrorKind::Compile,
+                })
+            }
+        };
+
+        builder::build(&self.condition, builder)?;
+        let else_addr = builder.function_builder.push_unknown(self.location.clone());
+        builder::build(&self.then_branch, builder)?;
+        let if_end_addr = builder.function_builder.push_unknown(self.location.clone());
+        builder
+            .function_builder
+            .set(else_addr, Opcode::JumpFalse(builder.function_builder.len()));
+        builder::build(else_branch, builder)?;
+        builder
+            .function_builder
+            .set(if_end_addr, Opcode::Jump(builder.function_builder.len()));
+        Ok(())
+    }
+
+    pub fn build_statement(&self, builder: &mut Builder) -> Result<(), Error> {
+        builder::build(&self.condition, builder)?;
+        let else_addr = builder.function_builder.push_unknown(self.location.clone());
+        builder::build_statement(&self.then_branch, builder)?;
+        match &self.else_branch {
+            Some(else_branch) => {
+                let if_end_addr = builder.function_builder.push_unknown(self.location.clone());
+                builder
+                    .function_builder
+                    .set(else_addr, Opcode::JumpFalse(builder.function_builder.len()));
+                builder::build_statement(else_branch, builder)?;
+                builder
+                    .function_builder
+                    .set(if_end_addr, Opcode::Jump(builder.function_builder.len()));
+            }
+            None => builder
+                .function_builder
+                .set(else_addr, Opcode::JumpFalse(builder.function_builder.len())),
+        }
+        Ok(())
+    }
+}
+
+fn build_loop_exit(builder: &mut Builder) {
+    let end = builder.function_builder.len();
+    for position in builder.function_builder.exit_loop() {
+        builder.function_builder.set(position, Opcode::Jump(end));
+    }
+}
+
+pub struct While {
+    pub condition: Box<Expression>,
+    pub body: Box<Expression>,
+    pub location: Range<usize>,
+}
+
+/*
+while a { b }
+
+loop_start:
+    {a}
+    JumpFalse loop_exit
+    {b}
+    Jump loop_start
+loop_exit:
+    Constant false
+loop_end:
+
+"break c" pushes {c} and jumps to loop_end, "continue" jumps to loop_start.
+*/
+
+impl While {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        let loop_start = builder.function_builder.len();
+        builder::build(&self.condition, builder)?;
+        let loop_exit_addr = builder.function_builder.push_unknown(self.location.clone());
+        builder.function_builder.enter_loop(loop_start);
+        builder::build_statement(&self.body, builder)?;
+        builder
+            .function_builder
+            .push(Opcode::Jump(loop_start), self.location.clone());
+        builder.function_builder.set(
+            loop_exit_addr,
+            Opcode::JumpFalse(builder.function_builder.len()),
+        );
+        build_constant(Value::Bool(false), self.location.clone(), builder);
+        build_loop_exit(builder);
+        Ok(())
+    }
+}
+
+pub struct Loop {
+    pub body: Box<Expression>,
+    pub location: Range<usize>,
+}
+
+/*
+loop { a }
+
+loop_start:
+    {a}
+    Jump loop_start
+loop_end:
+*/
+
+impl Loop {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        let loop_start = builder.function_builder.len();
+        builder.function_builder.enter_loop(loop_start);
+        builder::build_statement(&self.body, builder)?;
+        builder
+            .function_builder
+            .push(Opcode::Jump(loop_start), self.location.clone());
+        build_loop_exit(builder);
+        Ok(())
+    }
+}
+
+fn build_loop_jump_prefix(
+    builder: &mut Builder,
+    keyword: &str,
+    location: &Range<usize>,
+) -> Result<(), Error> {
+    let loop_pending = match builder.function_builder.loop_pending() {
+        Some(loop_pending) => loop_pending,
+        None => {
+            return Err(Error {
+                message: format!("\"{}\" outside of a loop.", keyword),
+                location: location.clone(),
+                kind: ErrorKind::Compile,
+            })
+        }
+    };
+    // Operands of enclosing expressions are never consumed after the jump.
+    for _ in loop_pending..builder.function_builder.pending() {
+        builder
+            .function_builder
+            .push(Opcode::Drop, location.clone());
+    }
+    Ok(())
+}
+
+pub struct Break {
+    pub expr: Option<Box<Expression>>,
+    pub location: Range<usize>,
+}
+
+impl Break {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        build_loop_jump_prefix(builder, "break", &self.location)?;
+        match &self.expr {
+            Some(expr) => builder::build(expr, builder)?,
+            None => build_constant(Value::Bool(false), self.location.clone(), builder),
+        }
+        let position = builder.function_builder.push_unknown(self.location.clone());
+        builder.function_builder.push_break(position);
+        Ok(())
+    }
+}
+
+pub struct Continue {
+    pub location: Range<usize>,
+}
+
+impl Continue {
+    pub fn build(&self, builder: &mut Builder) -> Result<(), Error> {
+        build_loop_jump_prefix(builder, "continue", &self.location)?;
+        let loop_start = builder.function_builder.loop_start().unwrap();
+        builder
+            .function_builder
+            .push(Opcode::Jump(loop_start), self.location.clone());
+        Ok(())
+    }
+}
+
+// Placeholder for code the parser failed to parse.
+pub struct Invalid {
+    pub location: Range<usize>,
+}
+
+impl Invalid {
+    pub fn build(&self, _builder: &mut Builder) -> Result<(), Error> {
+        Err(Error {
+            message: "Unable to build invalid expression.".to_string(),
+            location: self.location.clone(),
+            kind: ErrorKind::Compile,
+        })
+    }
+}
+
 pub enum Expression {
     Literal(Literal),
+    Group(Group),
+    Unary(Unary),
     Binary(Binary),
     BinaryLogic(BinaryLogic),
     Variable(Variable),
     Assignment(Assignment),
+    CompoundAssignment(CompoundAssignment),
     ExprList(ExprList),
+    Block(Block),
+    FunctionDeclaration(FunctionDeclaration),
+    Call(Call),
+    Return(Return),
+    If(If),
+    Array(Array),
+    Map(Map),
+    Index(Index),
+    IndexAssignment(IndexAssignment),
+    While(While),
+    Loop(Loop),
+    Break(Break),
+    Continue(Continue),
+    Invalid(Invalid),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        builder::Builder,
+        common::ErrorKind,
+        testing::{build_error, build_into, eval_text, runtime_error},
+    };
+
+    #[test]
+    fn initializer_sees_shadowed_variable() {
+        assert_eq!(eval_text("let x = 1, { let x = x + 1, x }"), "2");
+    }
+
+    #[test]
+    fn initializer_doesnt_see_new_variable() {
+        let error = build_error("{ let t = 4 }; { let y = y; y }");
+        assert_eq!(error.message, "Can't find variable \"y\".");
+    }
+
+    #[test]
+    fn failed_function_leaves_builder_balanced() {
+        for code in ["fn f(a, a) { 1 }", "fn f(a) { missing }"] {
+            let mut builder = Builder::new();
+            assert!(build_into(code, &mut builder).is_err());
+            // The entry point is current again, the params of "f" are gone.
+            assert_eq!(builder.function_builder.frame_size(), 0);
+        }
+    }
+
+    #[test]
+    fn functions_can_call_later_declared_functions() {
+        assert_eq!(eval_text("fn f() { g() } fn g() { 1 } f()"), "1");
+        assert_eq!(
+            eval_text(
+                "fn is_even(n) { if n == 0 { true } else { is_odd(n - 1) } }
+                 fn is_odd(n) { if n == 0 { false } else { is_even(n - 1) } }
+                 is_even(10)"
+            ),
+            "true"
+        );
+        assert_eq!(eval_text("{ fn h() { 2 } }; h()"), "2");
+    }
+
+    #[test]
+    fn errors_about_groups_span_the_group() {
+        assert_eq!(runtime_error("(true) + 1").location, 0..8);
+        assert_eq!(runtime_error("1 + (true)").location, 2..10);
+        assert_eq!(runtime_error("let x = true; -(x)").location, 14..18);
+        assert_eq!(runtime_error("let x = 1; (x) && true").location, 11..17);
+    }
+
+    #[test]
+    fn function_declared_twice_in_list() {
+        let error = build_error("fn f() { 1 } let a = f(); fn f() { 2 } a");
+        assert_eq!(
+            error.message,
+            "Function \"f\" is already declared in this block."
+        );
+        assert_eq!(error.location.start, 29);
+    }
+
+    #[test]
+    fn if_chains_yield_taken_branch() {
+        assert_eq!(
+            eval_text("let x = if false { 1 } else if true { 2 } else { 3 }; x"),
+            "2"
+        );
+        assert_eq!(eval_text("if false { 1 } else { 3 }"), "3");
+        // Without a value expected, "if" doesn't need "else".
+        assert_eq!(eval_text("let x = 1; if true { x = 2 }; x"), "2");
+    }
+
+    #[test]
+    fn if_without_else_as_value() {
+        for (code, start) in [
+            ("let x = if false { 1 }; x", 8),
+            ("if true { 1 }", 0),
+            ("[if true { 1 }]", 1),
+        ] {
+            let error = build_error(code);
+            assert_eq!(
+                error.message, "\"if\" without \"else\" can't be used as a value.",
+                "{}",
+                code
+            );
+            assert_eq!(error.location, start..start + 2, "{}", code);
+        }
+    }
+
+    #[test]
+    fn break_leaves_loop_with_value() {
+        assert_eq!(eval_text("loop { 1 + break 7 }"), "7");
+        assert_eq!(eval_text("let i = 0; while i < 3 { i = i + 1 }"), "false");
+        assert_eq!(
+            eval_text(
+                "let i = 0; let s = 0; while i < 5 { i += 1; if i == 2 { continue }; s += i }; s"
+            ),
+            "13"
+        );
+    }
+
+    #[test]
+    fn break_and_continue_outside_loop() {
+        for (code, message, location) in [
+            ("break", "\"break\" outside of a loop.", 0..5),
+            ("break 1", "\"break\" outside of a loop.", 0..5),
+            ("continue", "\"continue\" outside of a loop.", 0..8),
+            // A function body doesn't see the loops around its declaration.
+            (
+                "loop { fn f() { break } }",
+                "\"break\" outside of a loop.",
+                16..21,
+            ),
+        ] {
+            let error = build_error(code);
+            assert_eq!(error.message, message, "{}", code);
+            assert_eq!(error.location, location, "{}", code);
+        }
+    }
+
+    #[test]
+    fn unary_operators() {
+        assert_eq!(eval_text("-5"), "-5");
+        assert_eq!(eval_text("-1.5"), "-1.5");
+        assert_eq!(eval_text("!false"), "true");
+        assert_eq!(eval_text("~5"), "-6");
+    }
+
+    #[test]
+    fn unary_operator_type_errors() {
+        for (code, message) in [
+            ("-true", "Can't negate bool, expected number."),
+            ("-\"a\"", "Can't negate string, expected number."),
+            (
+                "!1",
+                "Can't use integer in logical not operation, expected bool.",
+            ),
+            (
+                "!\"a\"",
+                "Can't use string in logical not operation, expected bool.",
+            ),
+            (
+                "~1.5",
+                "Can't use real in bitwise not operation, expected integer.",
+            ),
+        ] {
+            let error = runtime_error(code);
+            assert_eq!(error.message, message, "{}", code);
+            assert_eq!(error.kind, ErrorKind::Type, "{}", code);
+            assert_eq!(error.location, 0..1, "{}", code);
+        }
+    }
+
+    #[test]
+    fn compound_assignments() {
+        for (operator, result) in [
+            ("+=", "10"),
+            ("-=", "4"),
+            ("*=", "21"),
+            ("/=", "2"),
+            ("%=", "1"),
+            ("&=", "3"),
+            ("|=", "7"),
+            ("^=", "4"),
+            ("<<=", "56"),
+            (">>=", "0"),
+        ] {
+            // The assignment yields the stored value, as does the variable.
+            let code = format!("let x = 7; let y = x {} 3; [x, y]", operator);
+            assert_eq!(
+                eval_text(&code),
+                format!("[{}, {}]", result, result),
+                "{}",
+                code
+            );
+        }
+        assert_eq!(eval_text("let s = \"a\"; s += \"b\"; s"), "ab");
+    }
+
+    #[test]
+    fn compound_assignment_errors() {
+        let error = build_error("y += 1");
+        assert_eq!(error.message, "Can't find variable \"y\".");
+        let error = runtime_error("let x = 1; x += \"a\"");
+        assert_eq!(error.kind, ErrorKind::Type);
+        assert_eq!(error.location, 13..15);
+    }
 }
@@ -0,0 +1,366 @@
+use std::ops::Range;
+
+use crate::{
+    common::{Error, ErrorKind},
+    expression::*,
+    program::Opcode,
+    value::{
+        Addict, ArithmeticOrComparison, BinaryOperator, BitNot, Bitwise, Divide, Equal, Equality,
+        Greater, GreaterEqual, Less, LessEqual, Modulo, Multiply, Negate, Not, NotEqual,
+        OperatorResult, Shl, Shr, StringOrArithmetic, Subtract, UnaryOperator, Value, Xor,
+    },
+};
+
+fn eval_binary(opcode: Opcode, lhs: Value, rhs: Value) -> OperatorResult {
+    match opcode {
+        Opcode::Addict => StringOrArithmetic::<Addict>::eval(lhs, rhs),
+        Opcode::Subtract => ArithmeticOrComparison::<Subtract>::eval(lhs, rhs),
+        Opcode::Multiply => ArithmeticOrComparison::<Multiply>::eval(lhs, rhs),
+        Opcode::Divide => ArithmeticOrComparison::<Divide>::eval(lhs, rhs),
+        Opcode::Modulo => ArithmeticOrComparison::<Modulo>::eval(lhs, rhs),
+        Opcode::And => Bitwise::<crate::value::And>::eval(lhs, rhs),
+        Opcode::Or => Bitwise::<crate::value::Or>::eval(lhs, rhs),
+        Opcode::Xor => Bitwise::<Xor>::eval(lhs, rhs),
+        Opcode::Shl => Bitwise::<Shl>::eval(lhs, rhs),
+        Opcode::Shr => Bitwise::<Shr>::eval(lhs, rhs),
+        Opcode::Equal => Equality::<Equal>::eval(lhs, rhs),
+        Opcode::NotEqual => Equality::<NotEqual>::eval(lhs, rhs),
+        Opcode::Greater => StringOrArithmetic::<Greater>::eval(lhs, rhs),
+        Opcode::Less => StringOrArithmetic::<Less>::eval(lhs, rhs),
+        Opcode::GreaterEqual => StringOrArithmetic::<GreaterEqual>::eval(lhs, rhs),
+        Opcode::LessEqual => StringOrArithmetic::<LessEqual>::eval(lhs, rhs),
+        _ => unreachable!("{:?} is not a binary operator.", opcode),
+    }
+}
+
+fn eval_unary(opcode: Opcode, value: Value) -> OperatorResult {
+    match opcode {
+        Opcode::Negate => Negate::eval(value),
+        Opcode::Not => Not::eval(value),
+        Opcode::BitNot => BitNot::eval(value),
+        _ => unreachable!("{:?} is not an unary operator.", opcode),
+    }
+}
+
+// Failing operators stay in the tree, so they fail when the code runs, like
+// they would without folding. Code that never runs still works.
+fn create_literal(
+    result: OperatorResult,
+    location: Range<usize>,
+    unfolded: Expression,
+    warnings: &mut Vec<Error>,
+) -> Expression {
+    match result {
+        Ok(value) => Expression::Literal(Literal { value, location }),
+        Err((kind, message)) => {
+            warnings.push(Error {
+                message,
+                location,
+                kind,
+            });
+            unfolded
+        }
+    }
+}
+
+fn fold_box(mut expression: Box<Expression>, warnings: &mut Vec<Error>) -> Box<Expression> {
+    *expression = fold_expression(*expression, warnings);
+    expression
+}
+
+fn fold_option(
+    expression: Option<Box<Expression>>,
+    warnings: &mut Vec<Error>,
+) -> Option<Box<Expression>> {
+    expression.map(|expression| fold_box(expression, warnings))
+}
+
+fn fold_vec(expressions: Vec<Expression>, warnings: &mut Vec<Error>) -> Vec<Expression> {
+    expressions
+        .into_iter()
+        .map(|expression| fold_expression(expression, warnings))
+        .collect()
+}
+
+// Groups are kept around folded values, so errors about them still point at
+// the whole group.
+fn fold_group(group: Group, warnings: &mut Vec<Error>) -> Expression {
+    Expression::Group(Group {
+        expr: fold_box(group.expr, warnings),
+        location: group.location,
+    })
+}
+
+// Value of a folded expression made of literals only.
+fn as_literal(expression: &Expression) -> Option<&Value> {
+    match expression {
+        Expression::Literal(literal) => Some(&literal.value),
+        Expression::Group(group) => as_literal(&group.expr),
+        _ => None,
+    }
+}
+
+fn fold_unary(unary: Unary, warnings: &mut Vec<Error>) -> Expression {
+    let expr = fold_box(unary.expr, warnings);
+    match as_literal(&expr) {
+        Some(value) => {
+            let result = eval_unary(unary.opcode, value.clone());
+            let location = operation_location(&unary.location, &[&expr]);
+            let unfolded = Expression::Unary(Unary { expr, ..unary });
+            create_literal(result, location, unfolded, warnings)
+        }
+        None => Expression::Unary(Unary { expr, ..unary }),
+    }
+}
+
+fn fold_binary(binary: Binary, warnings: &mut Vec<Error>) -> Expression {
+    let lhs = fold_box(binary.lhs, warnings);
+    let rhs = fold_box(binary.rhs, warnings);
+    match (as_literal(&lhs), as_literal(&rhs)) {
+        (Some(lhs_value), Some(rhs_value)) => {
+            let result = eval_binary(binary.opcode, lhs_value.clone(), rhs_value.clone());
+            let location = operation_location(&binary.location, &[&lhs, &rhs]);
+            let unfolded = Expression::Binary(Binary { lhs, rhs, ..binary });
+            create_literal(result, location, unfolded, warnings)
+        }
+        _ => Expression::Binary(Binary { lhs, rhs, ..binary }),
+    }
+}
+
+// Constant left hand side decides the result or yields the right hand side.
+// A right hand side that is never evaluated is still kept unless it is a
+// literal, so building it reports unknown names and other build errors.
+fn fold_binary_logic(logic: BinaryLogic, warnings: &mut Vec<Error>) -> Expression {
+    let short_circuit = match logic.logic_type {
+        BinaryLogicType::And => false,
+        BinaryLogicType::Or => true,
+    };
+    let lhs = fold_box(logic.lhs, warnings);
+    let rhs = fold_box(logic.rhs, warnings);
+    match (as_literal(&lhs), as_literal(&rhs)) {
+        (Some(&Value::Bool(value)), Some(_)) if value == short_circuit => {
+            Expression::Literal(Literal {
+                value: Value::Bool(value),
+                location: logic.location,
+            })
+        }
+        (Some(&Value::Bool(value)), _) if value != short_circuit => *rhs,
+        (literal, _) => {
+            if let Some(value) = literal.filter(|value| !matches!(value, Value::Bool(_))) {
+                warnings.push(Error {
+                    message: format!("Expected bool value, but got {}.", value.type_name()),
+                    location: operation_location(&logic.location, &[&lhs]),
+                    kind: ErrorKind::Type,
+                });
+            }
+            Expression::BinaryLogic(BinaryLogic { lhs, rhs, ..logic })
+        }
+    }
+}
+
+fn fold_list(list: ExprList, warnings: &mut Vec<Error>) -> ExprList {
+    ExprList {
+        exprs: fold_vec(list.exprs, warnings),
+        ..list
+    }
+}
+
+fn fold_expression(expression: Expression, warnings: &mut Vec<Error>) -> Expression {
+    match expression {
+        Expression::Group(group) => fold_group(group, warnings),
+        Expression::Unary(unary) => fold_unary(unary, warnings),
+        Expression::Binary(binary) => fold_binary(binary, warnings),
+        Expression::BinaryLogic(logic) => fold_binary_logic(logic, warnings),
+        Expression::Assignment(assignment) => Expression::Assignment(Assignment {
+            expr: fold_box(assignment.expr, warnings),
+            ..assignment
+        }),
+        Expression::CompoundAssignment(assignment) => {
+            Expression::CompoundAssignment(CompoundAssignment {
+                expr: fold_box(assignment.expr, warnings),
+                ..assignment
+            })
+        }
+        Expression::ExprList(list) => Expression::ExprList(fold_list(list, warnings)),
+        Expression::Block(block) => Expression::Block(Block {
+            list: fold_list(block.list, warnings),
+        }),
+        Expression::FunctionDeclaration(declaration) => {
+            Expression::FunctionDeclaration(FunctionDeclaration {
+                body: fold_box(declaration.body, warnings),
+                ..declaration
+            })
+        }
+        Expression::Call(call) => Expression::Call(Call {
+            args: fold_vec(call.args, warnings),
+            ..call
+        }),
+        Expression::Return(ret) => Expression::Return(Return {
+            expr: fold_option(ret.expr, warnings),
+            ..ret
+        }),
+        Expression::If(if_expression) => Expression::If(If {
+            condition: fold_box(if_expression.condition, warnings),
+            then_branch: fold_box(if_expression.then_branch, warnings),
+            else_branch: fold_option(if_expression.else_branch, warnings),
+            ..if_expression
+        }),
+        Expression::Array(array) => Expression::Array(Array {
+            elements: fold_vec(array.elements, warnings),
+            ..array
+        }),
+        Expression::Map(map) => Expression::Map(Map {
+            entries: map
+                .entries
+                .into_iter()
+                .map(|(key, value)| {
+                    (
+                        fold_expression(key, warnings),
+                        fold_expression(value, warnings),
+                    )
+                })
+                .collect(),
+            ..map
+        }),
+        Expression::Index(index) => Expression::Index(Index {
+            container: fold_box(index.container, warnings),
+            index: fold_box(index.index, warnings),
+            ..index
+        }),
+        Expression::IndexAssignment(assignment) => Expression::IndexAssignment(IndexAssignment {
+            container: fold_box(assignment.container, warnings),
+            index: fold_box(assignment.index, warnings),
+            expr: fold_box(assignment.expr, warnings),
+            ..assignment
+        }),
+        Expression::While(while_expression) => Expression::While(While {
+            condition: fold_box(while_expression.condition, warnings),
+            body: fold_box(while_expression.body, warnings),
+            ..while_expression
+        }),
+        Expression::Loop(loop_expression) => Expression::Loop(Loop {
+            body: fold_box(loop_expression.body, warnings),
+            ..loop_expression
+        }),
+        Expression::Break(break_expression) => Expression::Break(Break {
+            expr: fold_option(break_expression.expr, warnings),
+            ..break_expression
+        }),
+        expression @ (Expression::Literal(_)
+        | Expression::Variable(_)
+        | Expression::Continue(_)
+        | Expression::Invalid(_)) => expression,
+    }
+}
+
+// Replaces subtrees made of literals only with their values. Operators that
+// would fail at runtime, like dividing by zero, are kept and returned as
+// warnings, since the code may never run.
+pub fn fold(expression: Expression) -> (Expression, Vec<Error>) {
+    let mut warnings = Vec::new();
+    let expression = fold_expression(expression, &mut warnings);
+    (expression, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        session::Session,
+        testing::{build_error, eval_in, parse, runtime_error, single},
+    };
+
+    // Folds code made of a single expression.
+    fn fold_code(code: &str) -> (Expression, Vec<Error>) {
+        let (expression, warnings) = fold(parse(code));
+        (single(expression), warnings)
+    }
+
+    #[test]
+    fn folds_constant_arithmetic() {
+        let (expression, warnings) = fold_code("1 + 2 * 3");
+        assert!(warnings.is_empty());
+        assert!(matches!(
+            expression,
+            Expression::Literal(Literal {
+                value: Value::Integer(7),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn folds_remainder_overflow() {
+        let (expression, warnings) = fold_code("(-9223372036854775807 - 1) % -1");
+        assert!(warnings.is_empty());
+        assert!(matches!(
+            expression,
+            Expression::Literal(Literal {
+                value: Value::Integer(0),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn failing_operator_is_kept_with_warning() {
+        let (expression, warnings) = fold_code("1 / 0");
+        assert!(matches!(expression, Expression::Binary(_)));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, ErrorKind::Arithmetic);
+        assert_eq!(warnings[0].location, 2..3);
+    }
+
+    #[test]
+    fn failing_operator_in_code_that_never_runs() {
+        let mut session = Session::new();
+        let value = eval_in(&mut session, "if false { 1 / 0 } else { 2 }");
+        assert!(matches!(value, Value::Integer(2)));
+        assert_eq!(session.take_warnings().len(), 1);
+
+        let value = eval_in(&mut session, "fn f() { -true } 3");
+        assert!(matches!(value, Value::Integer(3)));
+        assert_eq!(session.take_warnings().len(), 1);
+    }
+
+    #[test]
+    fn failing_operator_fails_when_evaluated() {
+        assert_eq!(runtime_error("1 % 0").kind, ErrorKind::Arithmetic);
+    }
+
+    #[test]
+    fn short_circuit_keeps_right_hand_side_errors() {
+        let error = build_error("false && missing()");
+        assert_eq!(error.message, "Can't find function \"missing\".");
+        let error = build_error("fn f(a) { a } true || f(1, 2, 3)");
+        assert_eq!(
+            error.message,
+            "Function \"f\" takes 1 argument(s), but 3 given."
+        );
+        assert!(build_error("true || nothere").message.contains("nothere"));
+    }
+
+    #[test]
+    fn short_circuit_folds_constant_operands() {
+        for (code, result) in [
+            ("false && true", false),
+            ("true || false", true),
+            ("true && false", false),
+            ("false || true", true),
+        ] {
+            assert!(matches!(
+                fold_code(code).0,
+                Expression::Literal(Literal {
+                    value: Value::Bool(value),
+                    ..
+                }) if value == result
+            ));
+        }
+        let mut session = Session::new();
+        assert_eq!(
+            eval_in(&mut session, "let x = 1; false && x == 1").to_string(),
+            "false"
+        );
+        assert_eq!(eval_in(&mut session, "true || x == 1").to_string(), "true");
+    }
+}
@@ -2,19 +2,65 @@ use std::collections::HashMap;
 
 use crate::program::Opcode;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Function {
     pub opcodes: Box<[Opcode]>,
     pub locations: Box<[std::ops::Range<usize>]>,
     pub frame_size: usize,
+    pub params_count: usize,
 }
 
+impl Function {
+    pub fn new(params_count: usize) -> Self {
+        Self {
+            opcodes: Box::new([]),
+            locations: Box::new([]),
+            frame_size: 0,
+            params_count,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Block {
+    locals_count: usize,
+    // Bindings overwritten by locals of this block, restored on exit.
+    shadowed: Vec<(String, Option<usize>)>,
+}
+
+impl Block {
+    fn new(locals_count: usize) -> Self {
+        Self {
+            locals_count,
+            shadowed: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LoopTarget {
+    start: usize,
+    pending: usize,
+    breaks: Vec<usize>,
+}
+
+#[derive(Clone)]
 pub struct FunctionBuilder {
     opcodes: Vec<Opcode>,
     locations: Vec<std::ops::Range<usize>>,
-    blocks: Vec<usize>,
+    blocks: Vec<Block>,
     frame_size: usize,
+    params_count: usize,
     locals: HashMap<String, usize>,
+    loops: Vec<LoopTarget>,
+    // Count of operand values left on the stack by enclosing expressions.
+    pending: usize,
+}
+
+impl Default for FunctionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FunctionBuilder {
@@ -22,9 +68,12 @@ impl FunctionBuilder {
         Self {
             opcodes: Vec::new(),
             locations: Vec::new(),
-            blocks: vec![0],
+            blocks: vec![Block::new(0)],
             frame_size: 0,
+            params_count: 0,
             locals: HashMap::new(),
+            loops: Vec::new(),
+            pending: 0,
         }
     }
 
@@ -47,39 +96,102 @@ impl FunctionBuilder {
         self.opcodes.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.opcodes.is_empty()
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
     pub fn enter_block(&mut self) {
-        self.blocks.push(*self.blocks.last().unwrap());
+        let locals_count = self.blocks.last().unwrap().locals_count;
+        self.blocks.push(Block::new(locals_count));
     }
 
     pub fn exit_block(&mut self) {
-        self.blocks.pop().unwrap();
+        let block = self.blocks.pop().unwrap();
+        for (name, index) in block.shadowed.into_iter().rev() {
+            match index {
+                Some(index) => self.locals.insert(name, index),
+                None => self.locals.remove(&name),
+            };
+        }
+    }
+
+    pub fn add_pending(&mut self, count: usize) {
+        self.pending += count;
+    }
+
+    pub fn remove_pending(&mut self, count: usize) {
+        self.pending -= count;
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn enter_loop(&mut self, start: usize) {
+        self.loops.push(LoopTarget {
+            start,
+            pending: self.pending,
+            breaks: Vec::new(),
+        });
+    }
+
+    pub fn exit_loop(&mut self) -> Vec<usize> {
+        self.loops.pop().unwrap().breaks
+    }
+
+    pub fn loop_start(&self) -> Option<usize> {
+        self.loops.last().map(|target| target.start)
+    }
+
+    pub fn loop_pending(&self) -> Option<usize> {
+        self.loops.last().map(|target| target.pending)
+    }
+
+    pub fn push_break(&mut self, position: usize) {
+        self.loops.last_mut().unwrap().breaks.push(position);
     }
 
     pub fn get_local(&self, name: &String) -> Option<usize> {
         self.locals.get(name).cloned()
     }
 
-    pub fn new_local(&mut self, name: &String) -> usize {
-        *self.blocks.last_mut().unwrap() += 1;
-        let local_position = *self.blocks.last().unwrap() - 1;
+    pub fn new_local(&mut self, name: &str) -> usize {
+        let block = self.blocks.last_mut().unwrap();
+        block.locals_count += 1;
+        let local_position = block.locals_count - 1;
         if local_position + 1 > self.frame_size {
             self.frame_size = local_position + 1;
         }
 
-        if let Some(index) = self.locals.get_mut(name) {
-            *index = local_position;
-        } else {
-            self.locals.insert(name.clone(), local_position);
-        }
+        let previous = self.locals.insert(name.to_string(), local_position);
+        block.shadowed.push((name.to_string(), previous));
 
         local_position
     }
 
-    pub fn build(self) -> Function {
-        Function {
-            opcodes: self.opcodes.into_boxed_slice(),
-            locations: self.locations.into_boxed_slice(),
+    pub fn new_param(&mut self, name: &str) -> usize {
+        self.params_count += 1;
+        self.new_local(name)
+    }
+
+    // Takes the code emitted so far, keeping locals so that more code can be
+    // emitted against the same frame later.
+    pub fn take_function(&mut self) -> Function {
+        let mut function = Function {
+            opcodes: std::mem::take(&mut self.opcodes).into_boxed_slice(),
+            locations: std::mem::take(&mut self.locations).into_boxed_slice(),
             frame_size: self.frame_size,
-        }
+            params_count: self.params_count,
+        };
+        crate::peephole::optimize(&mut function);
+        function
+    }
+
+    pub fn build(mut self) -> Function {
+        self.take_function()
     }
 }
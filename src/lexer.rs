@@ -1,13 +1,24 @@
 use std::{iter::Peekable, ops::Range};
 
+use crate::common::Error;
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Token {
     Integer,
     Real,
+    String,
     Identifier,
     True,
     False,
     Let,
+    Fn,
+    Return,
+    If,
+    Else,
+    While,
+    Loop,
+    Break,
+    Continue,
     Plus,                   // +
     Minus,                  // -
     Asterisk,               // *
@@ -18,47 +29,72 @@ pub enum Token {
     Ampersand,              // &
     VerticalBar,            // |
     Circumflex,             // ^
+    Exclamation,            // !
+    Tilde,                  // ~
     Less,                   // <
     Greater,                // >
     Equal,                  // =
     Comma,                  // ,
+    Semicolon,              // ;
+    Colon,                  // :
+    LeftParenthesis,        // (
+    RightParenthesis,       // )
+    LeftBrace,              // {
+    RightBrace,             // }
+    LeftBracket,            // [
+    RightBracket,           // ]
     LessEqual,              // <=
     GreaterEqual,           // >=
     EqualEqual,             // ==
     ExclamationEqual,       // !=
     AmpersandAmpersand,     // &&
     VerticalBarVerticalBar, // ||
+    PlusEqual,              // +=
+    MinusEqual,             // -=
+    AsteriskEqual,          // *=
+    SlashEqual,             // /=
+    PercentEqual,           // %=
+    AmpersandEqual,         // &=
+    VerticalBarEqual,       // |=
+    CircumflexEqual,        // ^=
+    LessLessEqual,          // <<=
+    GreaterGreaterEqual,    // >>=
     Unknown,
+    UnterminatedString,
+    UnterminatedComment,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TokenInfo {
     pub token: Token,
     pub location: std::ops::Range<usize>,
 }
 
+#[derive(Clone)]
 pub struct CodeIterator<'a> {
     source: &'a [u8],
     position: usize,
 }
 
 impl<'a> CodeIterator<'a> {
-    fn new(source: &'a [u8]) -> Self {
-        Self {
-            source,
-            position: 0,
-        }
+    fn new(source: &'a [u8], position: usize) -> Self {
+        Self { source, position }
     }
 
     fn peek(&self) -> Option<u8> {
         self.source.get(self.position).cloned()
     }
 
+    fn peek_next(&self) -> Option<u8> {
+        self.source.get(self.position + 1).cloned()
+    }
+
     fn skip(&mut self) {
         self.position += 1;
     }
 }
 
+#[derive(Clone)]
 pub struct TokenIterator<'a>(CodeIterator<'a>);
 
 fn is_whitespace(c: u8) -> bool {
@@ -70,6 +106,14 @@ fn find_keyword(word: &[u8]) -> Option<Token> {
         b"false" => Some(Token::False),
         b"true" => Some(Token::True),
         b"let" => Some(Token::Let),
+        b"fn" => Some(Token::Fn),
+        b"return" => Some(Token::Return),
+        b"if" => Some(Token::If),
+        b"else" => Some(Token::Else),
+        b"while" => Some(Token::While),
+        b"loop" => Some(Token::Loop),
+        b"break" => Some(Token::Break),
+        b"continue" => Some(Token::Continue),
         _ => None,
     }
 }
@@ -84,10 +128,20 @@ fn parse_single_token(c: u8) -> Token {
         b'&' => Token::Ampersand,
         b'|' => Token::VerticalBar,
         b'^' => Token::Circumflex,
+        b'!' => Token::Exclamation,
+        b'~' => Token::Tilde,
         b'<' => Token::Less,
         b'>' => Token::Greater,
         b'=' => Token::Equal,
         b',' => Token::Comma,
+        b';' => Token::Semicolon,
+        b':' => Token::Colon,
+        b'(' => Token::LeftParenthesis,
+        b')' => Token::RightParenthesis,
+        b'{' => Token::LeftBrace,
+        b'}' => Token::RightBrace,
+        b'[' => Token::LeftBracket,
+        b']' => Token::RightBracket,
         _ => Token::Unknown,
     }
 }
@@ -102,13 +156,33 @@ fn parse_double_token(c1: u8, c2: u8) -> Option<Token> {
         (b'<', b'=') => Some(Token::LessEqual),
         (b'&', b'&') => Some(Token::AmpersandAmpersand),
         (b'|', b'|') => Some(Token::VerticalBarVerticalBar),
+        (b'+', b'=') => Some(Token::PlusEqual),
+        (b'-', b'=') => Some(Token::MinusEqual),
+        (b'*', b'=') => Some(Token::AsteriskEqual),
+        (b'/', b'=') => Some(Token::SlashEqual),
+        (b'%', b'=') => Some(Token::PercentEqual),
+        (b'&', b'=') => Some(Token::AmpersandEqual),
+        (b'|', b'=') => Some(Token::VerticalBarEqual),
+        (b'^', b'=') => Some(Token::CircumflexEqual),
+        _ => None,
+    }
+}
+
+fn parse_triple_token(token: Token, c3: u8) -> Option<Token> {
+    match (token, c3) {
+        (Token::LessLess, b'=') => Some(Token::LessLessEqual),
+        (Token::GreaterGreater, b'=') => Some(Token::GreaterGreaterEqual),
         _ => None,
     }
 }
 
 impl<'a> TokenIterator<'a> {
     pub fn new(source: &'a [u8]) -> Self {
-        Self(CodeIterator::new(source))
+        Self::with_offset(source, 0)
+    }
+
+    pub fn with_offset(source: &'a [u8], position: usize) -> Self {
+        Self(CodeIterator::new(source, position))
     }
 
     fn skip_whitespaces(&mut self) -> Option<()> {
@@ -122,31 +196,109 @@ impl<'a> TokenIterator<'a> {
         }
     }
 
+    // Skips "// ..." or nestable "/* ... */" comment, returns false when there
+    // is no comment and error token for unterminated block comment.
+    fn skip_comment(&mut self) -> Result<bool, TokenInfo> {
+        match (self.0.peek(), self.0.peek_next()) {
+            (Some(b'/'), Some(b'/')) => {
+                while let Some(c) = self.0.peek() {
+                    if c == b'\n' {
+                        break;
+                    }
+                    self.0.skip();
+                }
+                Ok(true)
+            }
+            (Some(b'/'), Some(b'*')) => {
+                let begin = self.0.position;
+                let mut depth = 0usize;
+                loop {
+                    match (self.0.peek(), self.0.peek_next()) {
+                        (Some(b'/'), Some(b'*')) => {
+                            depth += 1;
+                            self.0.skip();
+                        }
+                        (Some(b'*'), Some(b'/')) => {
+                            depth -= 1;
+                            self.0.skip();
+                            if depth == 0 {
+                                self.0.skip();
+                                break Ok(true);
+                            }
+                        }
+                        (Some(_), _) => {}
+                        (None, _) => {
+                            break Err(TokenInfo {
+                                token: Token::UnterminatedComment,
+                                location: begin..(begin + 2),
+                            })
+                        }
+                    }
+                    self.0.skip();
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Reads the whole number-like run of characters, so malformed literals
+    // like "1.2.3" or "0x" stay one token and the parser reports them.
     fn read_number(&mut self) -> Option<TokenInfo> {
         let begin = self.0.position;
+        match (self.0.peek()?, self.0.peek_next()) {
+            (c, _) if c.is_ascii_digit() => {}
+            (b'.', Some(c)) if c.is_ascii_digit() => {}
+            _ => return None,
+        }
+        let is_prefixed =
+            self.0.peek() == Some(b'0') && matches!(self.0.peek_next(), Some(b'x' | b'o' | b'b'));
         let mut is_real = false;
         while let Some(c) = self.0.peek() {
-            if c.is_ascii_digit() || c == b'.' {
-                if c == b'.' {
-                    if is_real {
-                        break;
-                    } else {
-                        is_real = true;
+            match c {
+                b'.' if !is_prefixed => is_real = true,
+                b'e' | b'E' if !is_prefixed => {
+                    is_real = true;
+                    if matches!(self.0.peek_next(), Some(b'+' | b'-')) {
+                        self.0.skip();
                     }
                 }
-                self.0.skip();
-            } else {
-                break;
+                _ if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' => {}
+                _ => break,
             }
+            self.0.skip();
         }
-        if begin == self.0.position {
-            None
-        } else {
-            Some(TokenInfo {
-                token: if is_real { Token::Real } else { Token::Integer },
-                location: begin..self.0.position,
-            })
+        Some(TokenInfo {
+            token: if is_real { Token::Real } else { Token::Integer },
+            location: begin..self.0.position,
+        })
+    }
+
+    fn read_string(&mut self) -> Option<TokenInfo> {
+        let begin = self.0.position;
+        if self.0.peek()? != b'"' {
+            return None;
         }
+        self.0.skip();
+        let token = loop {
+            match self.0.peek() {
+                Some(b'"') => {
+                    self.0.skip();
+                    break Token::String;
+                }
+                Some(b'\\') => {
+                    self.0.skip();
+                    if self.0.peek().is_some() {
+                        self.0.skip();
+                    }
+                }
+                Some(_) => self.0.skip(),
+                None => break Token::UnterminatedString,
+            }
+        };
+        Some(TokenInfo {
+            token,
+            location: begin..self.0.position,
+        })
     }
 
     fn read_identifier(&mut self) -> Option<TokenInfo> {
@@ -177,6 +329,13 @@ impl<'a> TokenIterator<'a> {
         if let Some(c2) = self.0.peek() {
             if let Some(token) = parse_double_token(c1, c2) {
                 self.0.skip();
+                if let Some(token) = self.0.peek().and_then(|c3| parse_triple_token(token, c3)) {
+                    self.0.skip();
+                    return Some(TokenInfo {
+                        token,
+                        location: begin..(begin + 3),
+                    });
+                }
                 return Some(TokenInfo {
                     token,
                     location: begin..(begin + 2),
@@ -195,13 +354,23 @@ impl<'a> Iterator for TokenIterator<'a> {
     type Item = TokenInfo;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.skip_whitespaces()?;
+        loop {
+            self.skip_whitespaces()?;
+            match self.skip_comment() {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(token) => return Some(token),
+            }
+        }
         if let Some(token) = self.read_number() {
             return Some(token);
         }
         if let Some(token) = self.read_identifier() {
             return Some(token);
         }
+        if let Some(token) = self.read_string() {
+            return Some(token);
+        }
         self.read_simple()
     }
 }
@@ -209,13 +378,21 @@ impl<'a> Iterator for TokenIterator<'a> {
 pub struct PeekableTokenIterator<'a> {
     it: Peekable<TokenIterator<'a>>,
     source: &'a [u8],
+    errors: Vec<Error>,
 }
 
 impl<'a> PeekableTokenIterator<'a> {
     pub fn new(source: &'a [u8]) -> Self {
+        Self::with_offset(source, 0)
+    }
+
+    // Tokenizes only the code after `position`, locations stay relative to
+    // the whole source.
+    pub fn with_offset(source: &'a [u8], position: usize) -> Self {
         Self {
-            it: TokenIterator::new(source).peekable(),
+            it: TokenIterator::with_offset(source, position).peekable(),
             source,
+            errors: Vec::new(),
         }
     }
 
@@ -223,11 +400,84 @@ impl<'a> PeekableTokenIterator<'a> {
         self.it.peek()
     }
 
-    pub fn next(&mut self) -> Option<TokenInfo> {
-        self.it.next()
+    pub fn peek_second(&self) -> Option<TokenInfo> {
+        let mut it = self.it.clone();
+        it.next();
+        it.next()
     }
 
     pub fn slice(&self, location: Range<usize>) -> &[u8] {
         &self.source[location]
     }
+
+    pub fn end(&self) -> usize {
+        self.source.len()
+    }
+
+    // Start of the next token or the end of code.
+    pub fn position(&mut self) -> usize {
+        let end = self.end();
+        self.peek()
+            .map_or(end, |token_info| token_info.location.start)
+    }
+
+    pub fn tokens_from(&self, position: usize) -> TokenIterator<'a> {
+        TokenIterator::with_offset(self.source, position)
+    }
+
+    // Records an error the parser recovered from, the same error reported by
+    // several nested parsers is kept once.
+    pub fn report(&mut self, error: Error) {
+        if let Some(last) = self.errors.last() {
+            if last.location == error.location && last.message == error.message {
+                return;
+            }
+        }
+        self.errors.push(error);
+    }
+
+    pub fn take_errors(&mut self) -> Vec<Error> {
+        std::mem::take(&mut self.errors)
+    }
+}
+
+impl<'a> Iterator for PeekableTokenIterator<'a> {
+    type Item = TokenInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.it.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(code: &str) -> Vec<(Token, Range<usize>)> {
+        TokenIterator::new(code.as_bytes())
+            .map(|token_info| (token_info.token, token_info.location))
+            .collect()
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        assert_eq!(tokens("/* a /* b */ c */ 1"), [(Token::Integer, 18..19)]);
+        assert_eq!(
+            tokens("1 /**/ 2"),
+            [(Token::Integer, 0..1), (Token::Integer, 7..8)]
+        );
+        assert_eq!(tokens("// a /* b\n1"), [(Token::Integer, 10..11)]);
+    }
+
+    #[test]
+    fn unterminated_block_comment_is_reported_at_its_start() {
+        assert_eq!(
+            tokens("1 /* a /* b */"),
+            [(Token::Integer, 0..1), (Token::UnterminatedComment, 2..4)]
+        );
+        assert_eq!(tokens("/*"), [(Token::UnterminatedComment, 0..2)]);
+        let errors = crate::testing::parse_errors("2 /* x");
+        assert_eq!(errors[0].message, "Unterminated block comment.");
+        assert_eq!(errors[0].location, 2..4);
+    }
 }
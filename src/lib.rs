@@ -1,11 +1,21 @@
 pub mod builder;
+pub mod bytecode;
 pub mod common;
+pub mod diagnostic;
+pub mod disassembler;
 pub mod expression;
+pub mod fold;
 pub mod function;
 pub mod lexer;
 pub mod module;
 pub mod parser;
+pub mod peephole;
 pub mod program;
 pub mod repl;
+pub mod runner;
+pub mod session;
 pub mod state;
+#[cfg(test)]
+mod testing;
 pub mod value;
+pub mod verifier;
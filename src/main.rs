@@ -1,3 +1,28 @@
-fn main() {
-    iris::repl::run()
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage:
+    iris                              start the REPL
+    iris [run] <script> [args...]     run a script or a compiled .irisc file
+    iris compile <script> [output]    compile a script to a .irisc file";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => {
+            iris::repl::run();
+            ExitCode::SUCCESS
+        }
+        ["compile", path] => iris::runner::compile(path, None),
+        ["compile", path, output] => iris::runner::compile(path, Some(output)),
+        ["compile", ..] | ["run"] => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+        ["run", path, args @ ..] | [path, args @ ..] => {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            iris::runner::run(path, &args)
+        }
+    }
 }
@@ -2,38 +2,65 @@ use std::collections::HashMap;
 
 use crate::{function::Function, value::Value};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Module {
     pub functions: Box<[Function]>,
     pub constants: Box<[Value]>,
 }
 
-pub struct ModuleBuilder<'a> {
-    pub source: &'a [u8],
+#[derive(Clone)]
+pub struct ModuleBuilder {
     functions: Vec<Function>,
+    functions_map: HashMap<String, usize>,
     constants: Vec<Value>,
     constants_map: HashMap<Value, usize>,
 }
 
-impl<'a> ModuleBuilder<'a> {
-    pub fn new(source: &'a [u8]) -> Self {
+impl Default for ModuleBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ModuleBuilder {
+    pub fn new() -> Self {
         Self {
-            source,
             functions: Vec::new(),
+            functions_map: HashMap::new(),
             constants: Vec::new(),
             constants_map: HashMap::new(),
         }
     }
 
-    pub fn push_function(&mut self, function: Function) {
-        self.functions.push(function);
+    pub fn reserve_function(&mut self, params_count: usize) -> usize {
+        self.functions.push(Function::new(params_count));
+        self.functions.len() - 1
+    }
+
+    pub fn set_function(&mut self, index: usize, function: Function) {
+        self.functions[index] = function;
+    }
+
+    pub fn declare_function(&mut self, name: &str, params_count: usize) -> usize {
+        let index = self.reserve_function(params_count);
+        self.functions_map.insert(name.to_string(), index);
+        index
+    }
+
+    pub fn get_function(&self, name: &str) -> Option<usize> {
+        self.functions_map.get(name).cloned()
+    }
+
+    pub fn params_count(&self, index: usize) -> usize {
+        self.functions[index].params_count
     }
 
     pub fn push_constant(&mut self, value: Value) -> usize {
-        if let Some(&value) = self.constants_map.get(&value) {
-            value
+        if let Some(&index) = self.constants_map.get(&value) {
+            index
         } else {
-            self.constants_map.insert(value, self.constants.len());
+            self.constants_map
+                .insert(value.clone(), self.constants.len());
             self.constants.push(value);
             self.constants.len() - 1
         }
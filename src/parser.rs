@@ -1,26 +1,27 @@
-use std::{ops::Range, str::FromStr};
+use std::ops::Range;
 
 use crate::{
-    common::Error,
+    common::{Error, ErrorKind},
     expression::*,
-    lexer::{PeekableTokenIterator, Token, TokenInfo},
+    lexer::{PeekableTokenIterator, Token, TokenInfo, TokenIterator},
     program::Opcode,
     value::Value,
 };
 
 pub type ParseResult = Result<Expression, Error>;
 
-fn unexpected_end() -> Result<TokenInfo, Error> {
+fn unexpected_end(it: &PeekableTokenIterator) -> Result<TokenInfo, Error> {
     Err(Error {
-        message: format!("Unexpected end of code."),
-        location: 0..0,
+        message: "Unexpected end of code.".to_string(),
+        location: it.end()..it.end(),
+        kind: ErrorKind::Compile,
     })
 }
 
 fn expect(it: &mut PeekableTokenIterator) -> Result<TokenInfo, Error> {
     match it.next() {
         Some(token_info) => Ok(token_info),
-        None => unexpected_end(),
+        None => unexpected_end(it),
     }
 }
 
@@ -29,65 +30,259 @@ fn expect_concrete(
     token: Token,
     name: &str,
 ) -> Result<TokenInfo, Error> {
-    let token_info = expect(it)?;
-    if token_info.token != token {
-        Err(Error {
-            message: format!("Expected {}.", name),
-            location: token_info.location,
-        })
-    } else {
-        Ok(token_info)
+    // Mismatched token is left in place for error recovery.
+    match it.peek() {
+        Some(token_info) if token_info.token != token => {
+            Err(lexer_error(token_info).unwrap_or(Error {
+                message: format!("Expected {}.", name),
+                location: token_info.location.clone(),
+                kind: ErrorKind::Compile,
+            }))
+        }
+        _ => expect(it),
     }
 }
 
 fn unexpected(location: Range<usize>) -> ParseResult {
     Err(Error {
-        message: format!("Unexpected token."),
+        message: "Unexpected token.".to_string(),
         location,
+        kind: ErrorKind::Compile,
     })
 }
 
-fn unknown(location: Range<usize>) -> ParseResult {
-    Err(Error {
-        message: format!("Unknown character."),
-        location,
+// Error for tokens the lexer produces from malformed code.
+fn lexer_error(token_info: &TokenInfo) -> Option<Error> {
+    let message = match token_info.token {
+        Token::Unknown => "Unknown character.",
+        Token::UnterminatedString => "Unterminated string literal.",
+        Token::UnterminatedComment => "Unterminated block comment.",
+        _ => return None,
+    };
+    Some(Error {
+        message: message.to_string(),
+        location: token_info.location.clone(),
+        kind: ErrorKind::Compile,
     })
 }
 
-fn parse_u8_str<T: FromStr>(
-    it: &mut PeekableTokenIterator,
-    location: Range<usize>,
-) -> Result<T, Error> {
-    std::str::from_utf8(it.slice(location.clone()))
-        .unwrap()
-        .parse::<T>()
-        .map_err(|_| Error {
-            message: format!("Unable to parse literal value may be it so long."),
-            location,
-        })
+fn number_text<'a>(it: &'a PeekableTokenIterator, location: Range<usize>) -> &'a str {
+    std::str::from_utf8(it.slice(location)).unwrap()
+}
+
+fn invalid_digit(c: char, kind: &str, location: usize) -> Error {
+    Error {
+        message: format!("Invalid digit \"{}\" in {} literal.", c, kind),
+        location: location..(location + c.len_utf8()),
+        kind: ErrorKind::Compile,
+    }
 }
 
 fn create_literal(value: Value, location: Range<usize>) -> ParseResult {
     Ok(Expression::Literal(Literal { value, location }))
 }
 
+// Parses decimal, "0x" hexadecimal, "0o" octal and "0b" binary integers,
+// digits may be separated by "_".
 fn parse_integer(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
-    create_literal(
-        Value::Integer(parse_u8_str(it, location.clone())?),
-        location,
-    )
+    let text = number_text(it, location.clone());
+    let (radix, kind, prefix) = match text.get(0..2) {
+        Some("0x") => (16, "hexadecimal", 2),
+        Some("0o") => (8, "octal", 2),
+        Some("0b") => (2, "binary", 2),
+        _ => (10, "decimal", 0),
+    };
+
+    let mut value: Option<i64> = None;
+    for (index, c) in text.char_indices().skip(prefix) {
+        if c == '_' {
+            continue;
+        }
+        let digit = c
+            .to_digit(radix)
+            .ok_or_else(|| invalid_digit(c, kind, location.start + index))?;
+        let next = value
+            .unwrap_or(0)
+            .checked_mul(radix as i64)
+            .and_then(|value| value.checked_add(digit as i64));
+        value = Some(next.ok_or_else(|| Error {
+            message: "Integer literal is too large.".to_string(),
+            location: location.clone(),
+            kind: ErrorKind::Compile,
+        })?);
+    }
+
+    match value {
+        Some(value) => create_literal(Value::Integer(value), location),
+        None => Err(Error {
+            message: format!("Expected {} digits after \"{}\".", kind, &text[0..prefix]),
+            location,
+            kind: ErrorKind::Compile,
+        }),
+    }
 }
 
+// Parses reals like "1.5", "2E10" or "1_000.5e-3".
 fn parse_real(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
-    create_literal(Value::Real(parse_u8_str(it, location.clone())?), location)
+    let text = number_text(it, location.clone());
+    let mut has_dot = false;
+    let mut has_exponent = false;
+    let mut has_exponent_digits = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '_' | '+' | '-' => {}
+            '.' if has_dot || has_exponent => {
+                return Err(Error {
+                    message: "Unexpected \".\" in number literal.".to_string(),
+                    location: (location.start + index)..(location.start + index + 1),
+                    kind: ErrorKind::Compile,
+                })
+            }
+            '.' => has_dot = true,
+            'e' | 'E' if !has_exponent => has_exponent = true,
+            _ if c.is_ascii_digit() => has_exponent_digits = has_exponent,
+            _ => return Err(invalid_digit(c, "real", location.start + index)),
+        }
+    }
+    if has_exponent && !has_exponent_digits {
+        return Err(Error {
+            message: "Expected digits in exponent of real literal.".to_string(),
+            location,
+            kind: ErrorKind::Compile,
+        });
+    }
+
+    let digits: String = text.chars().filter(|&c| c != '_').collect();
+    let value = digits.parse::<f64>().map_err(|_| Error {
+        message: "Unable to parse real literal.".to_string(),
+        location: location.clone(),
+        kind: ErrorKind::Compile,
+    })?;
+    create_literal(Value::Real(value), location)
+}
+
+fn parse_escape(
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+    begin: usize,
+    offset: usize,
+) -> Result<char, Error> {
+    let invalid = |end: usize| Error {
+        message: "Invalid escape sequence in string literal.".to_string(),
+        location: (offset + begin)..(offset + end),
+        kind: ErrorKind::Compile,
+    };
+    let (index, c) = match chars.next() {
+        Some(next) => next,
+        None => return Err(invalid(begin + 1)),
+    };
+    match c {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        'r' => Ok('\r'),
+        '0' => Ok('\0'),
+        '\\' => Ok('\\'),
+        '"' => Ok('"'),
+        '\'' => Ok('\''),
+        'u' => {
+            if chars.next_if(|&(_, c)| c == '{').is_none() {
+                return Err(invalid(index + 1));
+            }
+            let mut code = 0u32;
+            let mut digits = 0;
+            loop {
+                match chars.next() {
+                    Some((index, '}')) if digits > 0 => {
+                        break char::from_u32(code).ok_or_else(|| invalid(index + 1))
+                    }
+                    Some((_, c)) if c.is_ascii_hexdigit() && digits < 6 => {
+                        code = code * 16 + c.to_digit(16).unwrap();
+                        digits += 1;
+                    }
+                    Some((index, c)) => break Err(invalid(index + c.len_utf8())),
+                    None => break Err(invalid(index + 1)),
+                }
+            }
+        }
+        _ => Err(invalid(index + c.len_utf8())),
+    }
+}
+
+fn parse_string(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let slice = it.slice((location.start + 1)..(location.end - 1));
+    let text = std::str::from_utf8(slice).map_err(|_| Error {
+        message: "String literal is not valid UTF-8.".to_string(),
+        location: location.clone(),
+        kind: ErrorKind::Compile,
+    })?;
+
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        if c == '\\' {
+            result.push(parse_escape(&mut chars, index, location.start + 1)?);
+        } else {
+            result.push(c);
+        }
+    }
+    create_literal(Value::String(result.into()), location)
+}
+
+fn parse_name(it: &mut PeekableTokenIterator, location: Range<usize>) -> String {
+    std::str::from_utf8(it.slice(location)).unwrap().to_string()
+}
+
+fn parse_call(it: &mut PeekableTokenIterator, name: String, location: Range<usize>) -> ParseResult {
+    it.next().unwrap();
+    let mut args = Vec::new();
+    if it.peek().map(|token_info| token_info.token) == Some(Token::RightParenthesis) {
+        it.next().unwrap();
+    } else {
+        loop {
+            args.push(parse_expression(it)?);
+            let token_info = expect(it)?;
+            match token_info.token {
+                Token::Comma => continue,
+                Token::RightParenthesis => break,
+                _ => {
+                    return Err(Error {
+                        message: "Expected \",\" or \")\".".to_string(),
+                        location: token_info.location,
+                        kind: ErrorKind::Compile,
+                    })
+                }
+            }
+        }
+    }
+    Ok(Expression::Call(Call {
+        name,
+        args,
+        location,
+    }))
+}
+
+fn compound_assignment_mapper(token: Token) -> Option<Opcode> {
+    match token {
+        Token::PlusEqual => Some(Opcode::Addict),
+        Token::MinusEqual => Some(Opcode::Subtract),
+        Token::AsteriskEqual => Some(Opcode::Multiply),
+        Token::SlashEqual => Some(Opcode::Divide),
+        Token::PercentEqual => Some(Opcode::Modulo),
+        Token::AmpersandEqual => Some(Opcode::And),
+        Token::VerticalBarEqual => Some(Opcode::Or),
+        Token::CircumflexEqual => Some(Opcode::Xor),
+        Token::LessLessEqual => Some(Opcode::Shl),
+        Token::GreaterGreaterEqual => Some(Opcode::Shr),
+        _ => None,
+    }
 }
 
 fn parse_identifier(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
-    let name = std::str::from_utf8(it.slice(location.clone()))
-        .unwrap()
-        .to_string();
+    let name = parse_name(it, location.clone());
 
     if let Some(token_info) = it.peek() {
+        if token_info.token == Token::LeftParenthesis {
+            return parse_call(it, name, location);
+        }
         if token_info.token == Token::Equal {
             let equal_token = it.next().unwrap();
             return Ok(Expression::Assignment(Assignment {
@@ -97,6 +292,15 @@ fn parse_identifier(it: &mut PeekableTokenIterator, location: Range<usize>) -> P
                 create_new_variable: false,
             }));
         }
+        if let Some(opcode) = compound_assignment_mapper(token_info.token) {
+            let location = it.next().unwrap().location;
+            return Ok(Expression::CompoundAssignment(CompoundAssignment {
+                name,
+                opcode,
+                expr: Box::new(parse_expression(it)?),
+                location,
+            }));
+        }
     }
     Ok(Expression::Variable(Variable { name, location }))
 }
@@ -104,9 +308,7 @@ fn parse_identifier(it: &mut PeekableTokenIterator, location: Range<usize>) -> P
 fn parse_let(it: &mut PeekableTokenIterator) -> ParseResult {
     let identifier_location = expect_concrete(it, Token::Identifier, "identifier")?.location;
     let equal_location = expect_concrete(it, Token::Equal, "\"=\"")?.location;
-    let name = std::str::from_utf8(it.slice(identifier_location))
-        .unwrap()
-        .to_string();
+    let name = parse_name(it, identifier_location);
     Ok(Expression::Assignment(Assignment {
         name,
         expr: Box::new(parse_expression(it)?),
@@ -115,21 +317,296 @@ fn parse_let(it: &mut PeekableTokenIterator) -> ParseResult {
     }))
 }
 
+fn parse_block_body(it: &mut PeekableTokenIterator) -> ParseResult {
+    if it.peek().map(|token_info| token_info.token) == Some(Token::RightBrace) {
+        it.next().unwrap();
+        return Ok(Expression::Block(Block {
+            list: ExprList {
+                exprs: Vec::new(),
+                discard_last: false,
+            },
+        }));
+    }
+    let list = parse_expressions(it);
+    expect_concrete(it, Token::RightBrace, "\"}\"")?;
+    Ok(Expression::Block(Block { list }))
+}
+
+fn parse_block(it: &mut PeekableTokenIterator) -> ParseResult {
+    expect_concrete(it, Token::LeftBrace, "\"{\"")?;
+    parse_block_body(it)
+}
+
+fn parse_params(it: &mut PeekableTokenIterator) -> Result<Vec<(String, Range<usize>)>, Error> {
+    expect_concrete(it, Token::LeftParenthesis, "\"(\"")?;
+    let mut params = Vec::new();
+    if it.peek().map(|token_info| token_info.token) == Some(Token::RightParenthesis) {
+        it.next().unwrap();
+        return Ok(params);
+    }
+    loop {
+        let location = expect_concrete(it, Token::Identifier, "parameter name")?.location;
+        params.push((parse_name(it, location.clone()), location));
+        let token_info = expect(it)?;
+        match token_info.token {
+            Token::Comma => continue,
+            Token::RightParenthesis => break Ok(params),
+            _ => {
+                break Err(Error {
+                    message: "Expected \",\" or \")\".".to_string(),
+                    location: token_info.location,
+                    kind: ErrorKind::Compile,
+                })
+            }
+        }
+    }
+}
+
+fn parse_function(it: &mut PeekableTokenIterator) -> ParseResult {
+    let location = expect_concrete(it, Token::Identifier, "function name")?.location;
+    let name = parse_name(it, location.clone());
+    let params = parse_params(it)?;
+    let body = parse_block(it)?;
+    Ok(Expression::FunctionDeclaration(FunctionDeclaration {
+        name,
+        params,
+        body: Box::new(body),
+        location,
+    }))
+}
+
+fn parse_if(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let condition = parse_expression(it)?;
+    let then_branch = parse_block(it)?;
+    let else_branch = if it.peek().map(|token_info| token_info.token) == Some(Token::Else) {
+        it.next().unwrap();
+        match it.peek() {
+            Some(token_info) if token_info.token == Token::If => {
+                let location = it.next().unwrap().location;
+                Some(Box::new(parse_if(it, location)?))
+            }
+            _ => Some(Box::new(parse_block(it)?)),
+        }
+    } else {
+        None
+    };
+    Ok(Expression::If(If {
+        condition: Box::new(condition),
+        then_branch: Box::new(then_branch),
+        else_branch,
+        location,
+    }))
+}
+
+fn parse_while(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let condition = parse_expression(it)?;
+    let body = parse_block(it)?;
+    Ok(Expression::While(While {
+        condition: Box::new(condition),
+        body: Box::new(body),
+        location,
+    }))
+}
+
+fn parse_loop(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let body = parse_block(it)?;
+    Ok(Expression::Loop(Loop {
+        body: Box::new(body),
+        location,
+    }))
+}
+
+fn is_expression_end(token_info: Option<&TokenInfo>) -> bool {
+    match token_info {
+        Some(token_info) => matches!(
+            token_info.token,
+            Token::Comma | Token::Semicolon | Token::RightBrace | Token::RightParenthesis
+        ),
+        None => true,
+    }
+}
+
+fn parse_return(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let expr = if is_expression_end(it.peek()) {
+        None
+    } else {
+        Some(Box::new(parse_expression(it)?))
+    };
+    Ok(Expression::Return(Return { expr, location }))
+}
+
+fn parse_break(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let expr = if is_expression_end(it.peek()) {
+        None
+    } else {
+        Some(Box::new(parse_expression(it)?))
+    };
+    Ok(Expression::Break(Break { expr, location }))
+}
+
+fn parse_group(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let expr = parse_expression(it)?;
+    let token_info = expect(it)?;
+    if token_info.token != Token::RightParenthesis {
+        return Err(Error {
+            message: "Expected \")\" to close group.".to_string(),
+            location: location.start..token_info.location.end,
+            kind: ErrorKind::Compile,
+        });
+    }
+    Ok(Expression::Group(Group {
+        expr: Box::new(expr),
+        location: location.start..token_info.location.end,
+    }))
+}
+
+fn parse_array(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let mut elements = Vec::new();
+    let end = loop {
+        if it.peek().map(|token_info| token_info.token) == Some(Token::RightBracket) {
+            break it.next().unwrap().location.end;
+        }
+        elements.push(parse_expression(it)?);
+        let token_info = expect(it)?;
+        match token_info.token {
+            Token::Comma => continue,
+            Token::RightBracket => break token_info.location.end,
+            _ => {
+                return Err(Error {
+                    message: "Expected \",\" or \"]\".".to_string(),
+                    location: token_info.location,
+                    kind: ErrorKind::Compile,
+                })
+            }
+        }
+    };
+    Ok(Expression::Array(Array {
+        elements,
+        location: location.start..end,
+    }))
+}
+
+// Tells a map from a block by the tokens after "{": a ":" outside of any
+// brackets before the first "," or "}" makes it a map, so keys can be any
+// expression like "-1" or "k + 1". An empty "{}" in expression or statement
+// position is an empty map, since an empty block there would only yield
+// false. Empty blocks are still written where a block is required, like
+// "if c {} else { 1 }" or "fn f() {}".
+fn is_map_start(tokens: impl Iterator<Item = TokenInfo>) -> bool {
+    let mut depth = 0usize;
+    for (index, token_info) in tokens.enumerate() {
+        match token_info.token {
+            Token::LeftParenthesis | Token::LeftBracket | Token::LeftBrace => depth += 1,
+            Token::RightParenthesis | Token::RightBracket if depth > 0 => depth -= 1,
+            Token::RightBrace if depth > 0 => depth -= 1,
+            Token::RightBrace => return index == 0,
+            Token::Colon if depth == 0 => return true,
+            Token::Comma | Token::Semicolon if depth == 0 => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn is_map_literal(it: &mut PeekableTokenIterator) -> bool {
+    let position = it.position();
+    is_map_start(it.tokens_from(position))
+}
+
+fn parse_map_key(it: &mut PeekableTokenIterator) -> ParseResult {
+    if let Some(token_info) = it.peek() {
+        if token_info.token == Token::Identifier
+            && it.peek_second().map(|token_info| token_info.token) == Some(Token::Colon)
+        {
+            let location = it.next().unwrap().location;
+            let name = parse_name(it, location.clone());
+            return create_literal(Value::String(name.into()), location);
+        }
+    }
+    parse_expression(it)
+}
+
+fn parse_map(it: &mut PeekableTokenIterator, location: Range<usize>) -> ParseResult {
+    let mut entries = Vec::new();
+    let end = loop {
+        if it.peek().map(|token_info| token_info.token) == Some(Token::RightBrace) {
+            break it.next().unwrap().location.end;
+        }
+        let key = parse_map_key(it)?;
+        expect_concrete(it, Token::Colon, "\":\"")?;
+        entries.push((key, parse_expression(it)?));
+        let token_info = expect(it)?;
+        match token_info.token {
+            Token::Comma => continue,
+            Token::RightBrace => break token_info.location.end,
+            _ => {
+                return Err(Error {
+                    message: "Expected \",\" or \"}\".".to_string(),
+                    location: token_info.location,
+                    kind: ErrorKind::Compile,
+                })
+            }
+        }
+    };
+    Ok(Expression::Map(Map {
+        entries,
+        location: location.start..end,
+    }))
+}
+
 fn parse_primary(it: &mut PeekableTokenIterator) -> ParseResult {
+    // Separators and closing brackets are left in place for error recovery.
+    if let Some(token_info) = it.peek() {
+        if matches!(
+            token_info.token,
+            Token::Comma
+                | Token::Semicolon
+                | Token::RightParenthesis
+                | Token::RightBracket
+                | Token::RightBrace
+        ) {
+            return unexpected(token_info.location.clone());
+        }
+    }
     let token_info = expect(it)?;
+    if let Some(error) = lexer_error(&token_info) {
+        return Err(error);
+    }
 
     match token_info.token {
         Token::Integer => parse_integer(it, token_info.location),
         Token::Real => parse_real(it, token_info.location),
+        Token::String => parse_string(it, token_info.location),
         Token::True => create_literal(Value::Bool(true), token_info.location),
         Token::False => create_literal(Value::Bool(false), token_info.location),
-        Token::Unknown => unknown(token_info.location),
         Token::Identifier => parse_identifier(it, token_info.location),
         Token::Let => parse_let(it),
+        Token::Fn => parse_function(it),
+        Token::Return => parse_return(it, token_info.location),
+        Token::LeftBrace if is_map_literal(it) => parse_map(it, token_info.location),
+        Token::LeftBrace => parse_block_body(it),
+        Token::LeftParenthesis => parse_group(it, token_info.location),
+        Token::LeftBracket => parse_array(it, token_info.location),
+        Token::If => parse_if(it, token_info.location),
+        Token::While => parse_while(it, token_info.location),
+        Token::Loop => parse_loop(it, token_info.location),
+        Token::Break => parse_break(it, token_info.location),
+        Token::Continue => Ok(Expression::Continue(Continue {
+            location: token_info.location,
+        })),
         _ => unexpected(token_info.location),
     }
 }
 
+fn unary_mapper(token: Token) -> Option<Opcode> {
+    match token {
+        Token::Minus => Some(Opcode::Negate),
+        Token::Exclamation => Some(Opcode::Not),
+        Token::Tilde => Some(Opcode::BitNot),
+        _ => None,
+    }
+}
+
 fn and_mapper(token: Token) -> Option<BinaryLogicType> {
     if token == Token::AmpersandAmpersand {
         Some(BinaryLogicType::And)
@@ -238,8 +715,54 @@ fn parse_binary_logic(
     Ok(lhs)
 }
 
+fn parse_postfix(it: &mut PeekableTokenIterator) -> ParseResult {
+    let mut expr = parse_primary(it)?;
+    while let Some(token_info) = it.peek() {
+        if token_info.token != Token::LeftBracket {
+            break;
+        }
+        let begin = it.next().unwrap().location.start;
+        let index = parse_expression(it)?;
+        let end = expect_concrete(it, Token::RightBracket, "\"]\"")?
+            .location
+            .end;
+
+        if it.peek().map(|token_info| token_info.token) == Some(Token::Equal) {
+            let equal_token = it.next().unwrap();
+            return Ok(Expression::IndexAssignment(IndexAssignment {
+                container: Box::new(expr),
+                index: Box::new(index),
+                expr: Box::new(parse_expression(it)?),
+                location: equal_token.location,
+            }));
+        }
+
+        expr = Expression::Index(Index {
+            container: Box::new(expr),
+            index: Box::new(index),
+            location: begin..end,
+        });
+    }
+    Ok(expr)
+}
+
+fn parse_unary(it: &mut PeekableTokenIterator) -> ParseResult {
+    if let Some(token_info) = it.peek() {
+        if let Some(opcode) = unary_mapper(token_info.token) {
+            let location = it.next().unwrap().location;
+            let expr = parse_unary(it)?;
+            return Ok(Expression::Unary(Unary {
+                expr: Box::new(expr),
+                opcode,
+                location,
+            }));
+        }
+    }
+    parse_postfix(it)
+}
+
 fn parse_bitwise(it: &mut PeekableTokenIterator) -> ParseResult {
-    parse_binary(it, parse_primary, bitwise_mapper)
+    parse_binary(it, parse_unary, bitwise_mapper)
 }
 
 fn parse_factor(it: &mut PeekableTokenIterator) -> ParseResult {
@@ -270,35 +793,357 @@ fn parse_expression(it: &mut PeekableTokenIterator) -> ParseResult {
     parse_binary_or(it)
 }
 
-fn parse_expression_list(it: &mut PeekableTokenIterator) -> ParseResult {
-    let mut exprs = vec![parse_expression(it)?];
+fn is_block_statement(it: &mut PeekableTokenIterator) -> bool {
+    match it.peek().map(|token_info| token_info.token) {
+        Some(Token::If | Token::While | Token::Loop | Token::Fn) => true,
+        Some(Token::LeftBrace) => {
+            let position = it.position();
+            !is_map_start(it.tokens_from(position).skip(1))
+        }
+        _ => false,
+    }
+}
 
-    while let Some(token_info) = it.peek() {
-        if token_info.token == Token::Comma {
+// Parses an expression in statement position. A statement starting with a
+// block-like expression ends at its closing "}", so "if c { a } [1]" is two
+// statements and the next one needs no separator. Returns whether the
+// statement was block-like.
+fn parse_statement(it: &mut PeekableTokenIterator) -> Result<(Expression, bool), Error> {
+    if is_block_statement(it) {
+        Ok((parse_primary(it)?, true))
+    } else {
+        Ok((parse_expression(it)?, false))
+    }
+}
+
+fn closing_bracket(token: Token) -> Option<Token> {
+    match token {
+        Token::LeftParenthesis => Some(Token::RightParenthesis),
+        Token::LeftBracket => Some(Token::RightBracket),
+        Token::LeftBrace => Some(Token::RightBrace),
+        _ => None,
+    }
+}
+
+fn track_brackets(brackets: &mut Vec<Token>, token: Token) {
+    if let Some(closing) = closing_bracket(token) {
+        brackets.push(closing);
+    } else if let Some(index) = brackets.iter().rposition(|&closing| closing == token) {
+        // Closes brackets left open inside, like "(" in "[1, (2]".
+        brackets.truncate(index);
+    }
+}
+
+// Finds the next "," or ";" or "}" outside of "brackets" and brackets opened after
+// them, an unmatched "}" always stops the search as it closes a block.
+fn find_synchronization(tokens: TokenIterator, mut brackets: Vec<Token>) -> Option<usize> {
+    for token_info in tokens {
+        let is_stop = match token_info.token {
+            Token::Comma | Token::Semicolon => brackets.is_empty(),
+            Token::RightBrace => !brackets.contains(&Token::RightBrace),
+            _ => false,
+        };
+        if is_stop {
+            return Some(token_info.location.start);
+        }
+        track_brackets(&mut brackets, token_info.token);
+    }
+    None
+}
+
+// Skips tokens until the next "," or ";" or "}" of the expression list the failed
+// expression started at "begin", so parsing can continue after an error.
+// Returns the end offset of the skipped code.
+fn synchronize(it: &mut PeekableTokenIterator, begin: usize, mut end: usize) -> usize {
+    let position = it.position();
+    let mut brackets = Vec::new();
+    for token_info in it
+        .tokens_from(begin)
+        .take_while(|token_info| token_info.location.start < position)
+    {
+        track_brackets(&mut brackets, token_info.token);
+    }
+
+    // Brackets left open by a broken expression may never be closed.
+    let stop = find_synchronization(it.tokens_from(position), brackets)
+        .or_else(|| find_synchronization(it.tokens_from(position), Vec::new()));
+
+    while it.position() != stop.unwrap_or(it.end()) {
+        end = it.next().unwrap().location.end;
+    }
+    end
+}
+
+fn is_list_end(token_info: Option<&TokenInfo>) -> bool {
+    match token_info {
+        Some(token_info) => token_info.token == Token::RightBrace,
+        None => true,
+    }
+}
+
+// Parses statements separated by "," or terminated by ";". A ";" after the
+// last statement discards its value, so the list yields false.
+fn parse_expressions(it: &mut PeekableTokenIterator) -> ExprList {
+    let mut exprs = Vec::new();
+
+    loop {
+        let begin = it.position();
+        let is_block = match parse_statement(it) {
+            Ok((expr, is_block)) => {
+                exprs.push(expr);
+                is_block
+            }
+            Err(error) => {
+                let location = error.location.clone();
+                it.report(error);
+                let end = synchronize(it, begin, location.end);
+                exprs.push(Expression::Invalid(Invalid {
+                    location: location.start..end.max(location.end),
+                }));
+                false
+            }
+        };
+
+        match it.peek().map(|token_info| token_info.token) {
+            Some(Token::Comma) => {
+                it.next().unwrap();
+            }
+            Some(Token::Semicolon) => {
+                while it.peek().map(|token_info| token_info.token) == Some(Token::Semicolon) {
+                    it.next().unwrap();
+                }
+                if is_list_end(it.peek()) {
+                    break ExprList {
+                        exprs,
+                        discard_last: true,
+                    };
+                }
+            }
+            _ if is_block && !is_list_end(it.peek()) => {}
+            _ => {
+                break ExprList {
+                    exprs,
+                    discard_last: false,
+                }
+            }
+        }
+    }
+}
+
+fn unexpected_token(token_info: TokenInfo) -> Error {
+    lexer_error(&token_info).unwrap_or(Error {
+        message: "Expected end, but found token.".to_string(),
+        location: token_info.location,
+        kind: ErrorKind::Compile,
+    })
+}
+
+// Parses the whole code, collecting all errors instead of stopping at the
+// first one. Unparsable parts are kept in the tree as invalid expressions.
+pub fn parse_recovering(it: &mut PeekableTokenIterator) -> (Expression, Vec<Error>) {
+    let mut list = parse_expressions(it);
+
+    while let Some(token_info) = it.next() {
+        let location = token_info.location.clone();
+        it.report(unexpected_token(token_info));
+        synchronize(it, location.end, location.end);
+        if matches!(
+            it.peek().map(|token_info| token_info.token),
+            Some(Token::Comma | Token::Semicolon)
+        ) {
             it.next().unwrap();
-            exprs.push(parse_expression(it)?);
-        } else {
-            break;
+            if !is_list_end(it.peek()) {
+                let rest = parse_expressions(it);
+                list.exprs.extend(rest.exprs);
+                list.discard_last = rest.discard_last;
+            }
         }
     }
 
-    Ok(Expression::ExprList(ExprList { exprs }))
+    (Expression::ExprList(list), it.take_errors())
 }
 
 pub fn parse(it: &mut PeekableTokenIterator) -> ParseResult {
-    let result = parse_expression_list(it)?;
+    let (result, mut errors) = parse_recovering(it);
+    if errors.is_empty() {
+        Ok(result)
+    } else {
+        Err(errors.swap_remove(0))
+    }
+}
 
-    match it.next() {
-        Some(token_info) => {
-            if token_info.token == Token::Unknown {
-                unknown(token_info.location)
-            } else {
-                Err(Error {
-                    message: format!("Expected end, but found token."),
-                    location: token_info.location,
-                })
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn parse_code(code: &str) -> Expression {
+        testing::single(testing::parse(code))
+    }
+
+    #[test]
+    fn empty_braces_are_map_where_value_is_expected() {
+        assert!(matches!(parse_code("{}"), Expression::Map(_)));
+        assert!(matches!(parse_code("[{}]"), Expression::Array(_)));
+        assert!(matches!(parse_code("{ x }"), Expression::Block(_)));
+    }
+
+    #[test]
+    fn empty_braces_are_block_where_block_is_required() {
+        match parse_code("if true {} else { 1 }") {
+            Expression::If(if_expression) => {
+                assert!(matches!(*if_expression.then_branch, Expression::Block(_)))
+            }
+            _ => panic!("expected if"),
+        }
+    }
+
+    #[test]
+    fn map_keys_can_be_any_expression() {
+        assert!(matches!(parse_code("{ -1: \"a\" }"), Expression::Map(_)));
+        assert!(matches!(parse_code("{ [1][0]: 2 }"), Expression::Map(_)));
+        assert!(matches!(parse_code("{ (1 + 2): 3 }"), Expression::Map(_)));
+        assert_eq!(testing::eval_text("{ -1: \"a\" }[-1]"), "a");
+        assert_eq!(testing::eval_text("let k = 1; { k + 1: 2 }[2]"), "2");
+        assert_eq!(testing::eval_text("{ [1][0]: 2 }[1]"), "2");
+    }
+
+    #[test]
+    fn colons_inside_brackets_dont_make_a_map() {
+        assert!(matches!(parse_code("{ [{ a: 1 }] }"), Expression::Block(_)));
+        assert!(matches!(
+            parse_code("{ f({ a: 1 }) }"),
+            Expression::Block(_)
+        ));
+        assert!(matches!(
+            parse_code("{ x; { a: 1 } }"),
+            Expression::Block(_)
+        ));
+    }
+
+    fn parse_recovering_code(code: &str) -> (Vec<Expression>, Vec<(String, Range<usize>)>) {
+        let mut it = PeekableTokenIterator::new(code.as_bytes());
+        let (expr, errors) = parse_recovering(&mut it);
+        let errors = errors
+            .into_iter()
+            .map(|error| (error.message, error.location))
+            .collect();
+        match expr {
+            Expression::ExprList(list) => (list.exprs, errors),
+            _ => panic!("expected expression list"),
+        }
+    }
+
+    fn invalid_location(expr: &Expression) -> Range<usize> {
+        match expr {
+            Expression::Invalid(invalid) => invalid.location.clone(),
+            _ => panic!("expected invalid expression"),
+        }
+    }
+
+    #[test]
+    fn recovers_after_each_broken_statement() {
+        let (exprs, errors) = parse_recovering_code("let = 5, 1 + , 3 )");
+        assert_eq!(
+            errors,
+            [
+                ("Expected identifier.".to_string(), 4..5),
+                ("Unexpected token.".to_string(), 13..14),
+                ("Expected end, but found token.".to_string(), 17..18),
+            ]
+        );
+        assert_eq!(exprs.len(), 3);
+        assert_eq!(invalid_location(&exprs[0]), 4..7);
+        assert_eq!(invalid_location(&exprs[1]), 13..14);
+        assert!(matches!(exprs[2], Expression::Literal(_)));
+    }
+
+    #[test]
+    fn recovers_from_bracket_left_open_in_block() {
+        for (code, error) in [
+            ("{ (1, 2 }, 3", ("Expected \")\" to close group.", 2..5)),
+            ("{ [1, 2; x }, 3", ("Expected \",\" or \"]\".", 7..8)),
+        ] {
+            let (exprs, errors) = parse_recovering_code(code);
+            assert_eq!(errors, [(error.0.to_string(), error.1)], "{}", code);
+            assert_eq!(exprs.len(), 2, "{}", code);
+            match &exprs[0] {
+                Expression::Block(block) => {
+                    assert!(matches!(block.list.exprs[0], Expression::Invalid(_)))
+                }
+                _ => panic!("expected block in {}", code),
             }
+            // The code after the block is parsed normally.
+            assert!(matches!(exprs[1], Expression::Literal(_)), "{}", code);
+        }
+    }
+
+    fn parse_error(code: &str) -> (String, Range<usize>) {
+        let error = testing::parse_errors(code).remove(0);
+        (error.message, error.location)
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(testing::eval_text("1_000"), "1000");
+        assert_eq!(testing::eval_text("0x1F"), "31");
+        assert_eq!(testing::eval_text(".5"), "0.5");
+        assert_eq!(testing::eval_text("1.5e-3"), "0.0015");
+    }
+
+    #[test]
+    fn malformed_number_literals() {
+        for (code, message, location) in [
+            ("0x", "Expected hexadecimal digits after \"0x\".", 0..2),
+            ("1.2.3", "Unexpected \".\" in number literal.", 3..4),
+            ("0b102", "Invalid digit \"2\" in binary literal.", 4..5),
+            ("1e", "Expected digits in exponent of real literal.", 0..2),
+            (
+                "99999999999999999999",
+                "Integer literal is too large.",
+                0..20,
+            ),
+        ] {
+            assert_eq!(
+                parse_error(code),
+                (message.to_string(), location),
+                "{}",
+                code
+            );
         }
-        None => Ok(result),
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        assert_eq!(testing::eval_text("\"\\u{41}\""), "A");
+        assert_eq!(
+            testing::eval_text("\"\\u{10FFFF}\" == \"\\u{10ffff}\""),
+            "true"
+        );
+        let message = "Invalid escape sequence in string literal.".to_string();
+        // No digits, more than 6 digits and a surrogate code point.
+        assert_eq!(parse_error("\"\\u{}\""), (message.clone(), 1..5));
+        assert_eq!(parse_error("\"\\u{1234567}\""), (message.clone(), 1..11));
+        assert_eq!(parse_error("\"\\u{D800}\""), (message, 1..9));
+    }
+
+    #[test]
+    fn semicolon_after_last_statement_discards_value() {
+        assert_eq!(testing::eval_text("1, 2"), "2");
+        assert_eq!(testing::eval_text("1; 2"), "2");
+        assert_eq!(testing::eval_text("1;"), "false");
+        assert_eq!(testing::eval_text("1;;"), "false");
+        assert_eq!(testing::eval_text("{ 1; }"), "false");
+        assert_eq!(testing::parse_errors("1 2").len(), 1);
+    }
+
+    #[test]
+    fn block_like_statements_need_no_separator() {
+        assert_eq!(testing::eval_text("if true { 1 } 2"), "2");
+        assert_eq!(testing::eval_text("{ 1 } 2"), "2");
+        assert_eq!(testing::eval_text("while false {} 3"), "3");
+        assert_eq!(testing::eval_text("fn f() { 4 } f()"), "4");
+        // "{ 1 } [0]" in value position indexes the block's value.
+        assert_eq!(testing::eval_text("let a = { [5] } [0]; a"), "5");
     }
 }
@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+
+use crate::{function::Function, program::Opcode};
+
+fn jump_target(opcode: Opcode) -> Option<usize> {
+    match opcode {
+        Opcode::Jump(position) | Opcode::JumpFalse(position) | Opcode::JumpTrue(position) => {
+            Some(position)
+        }
+        _ => None,
+    }
+}
+
+fn with_jump_target(opcode: Opcode, position: usize) -> Opcode {
+    match opcode {
+        Opcode::Jump(_) => Opcode::Jump(position),
+        Opcode::JumpFalse(_) => Opcode::JumpFalse(position),
+        Opcode::JumpTrue(_) => Opcode::JumpTrue(position),
+        _ => opcode,
+    }
+}
+
+// Follows a chain of unconditional jumps, the step limit stops on cycles
+// like the one of an empty "loop {}".
+fn final_target(opcodes: &[Opcode], mut position: usize) -> usize {
+    for _ in 0..opcodes.len() {
+        match opcodes.get(position) {
+            Some(&Opcode::Jump(next)) if next != position => position = next,
+            _ => break,
+        }
+    }
+    position
+}
+
+fn thread_jumps(opcodes: &mut [Opcode]) {
+    for index in 0..opcodes.len() {
+        if let Some(position) = jump_target(opcodes[index]) {
+            opcodes[index] = with_jump_target(opcodes[index], final_target(opcodes, position));
+        }
+    }
+}
+
+// "Push, StoreLocal, Drop" left by an assignment used as a statement stores
+// the value just like a single "StoreLocal" does. Jumps into the middle of
+// the pattern would expect the copy made by "Push", so those are kept.
+fn is_redundant_store(opcodes: &[Opcode], index: usize, targets: &HashSet<usize>) -> bool {
+    matches!(
+        opcodes[index..],
+        [Opcode::Push, Opcode::StoreLocal(_), Opcode::Drop, ..]
+    ) && !targets.contains(&(index + 1))
+        && !targets.contains(&(index + 2))
+}
+
+// Rewrites redundant opcode sequences of the function, keeping jump targets
+// and the locations table in sync with the opcodes.
+pub fn optimize(function: &mut Function) {
+    let mut opcodes = std::mem::take(&mut function.opcodes).into_vec();
+    thread_jumps(&mut opcodes);
+
+    let targets: HashSet<usize> = opcodes
+        .iter()
+        .filter_map(|&opcode| jump_target(opcode))
+        .collect();
+    let mut kept = vec![true; opcodes.len()];
+    let mut index = 0;
+    while index < opcodes.len() {
+        if is_redundant_store(&opcodes, index, &targets) {
+            kept[index] = false;
+            kept[index + 2] = false;
+            index += 3;
+        } else {
+            index += 1;
+        }
+    }
+
+    // New position of every old one and of the end, removed opcodes map to
+    // the next kept one.
+    let mut positions = Vec::with_capacity(opcodes.len() + 1);
+    let mut position = 0;
+    for &is_kept in &kept {
+        positions.push(position);
+        if is_kept {
+            position += 1;
+        }
+    }
+    positions.push(position);
+
+    let locations = std::mem::take(&mut function.locations).into_vec();
+    let (opcodes, locations): (Vec<_>, Vec<_>) = opcodes
+        .into_iter()
+        .zip(locations)
+        .zip(kept)
+        .filter(|&(_, is_kept)| is_kept)
+        .map(|((opcode, location), _)| match jump_target(opcode) {
+            Some(position) => (with_jump_target(opcode, positions[position]), location),
+            None => (opcode, location),
+        })
+        .unzip();
+    function.opcodes = opcodes.into_boxed_slice();
+    function.locations = locations.into_boxed_slice();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Function with the location of every opcode equal to its position.
+    fn function(opcodes: &[Opcode]) -> Function {
+        Function {
+            opcodes: opcodes.into(),
+            locations: (0..opcodes.len()).map(|i| i..i + 1).collect(),
+            frame_size: 1,
+            params_count: 0,
+        }
+    }
+
+    fn optimized(opcodes: &[Opcode]) -> Function {
+        let mut function = function(opcodes);
+        optimize(&mut function);
+        function
+    }
+
+    #[test]
+    fn removes_redundant_store() {
+        let function = optimized(&[
+            Opcode::Constant(0),
+            Opcode::Push,
+            Opcode::StoreLocal(0),
+            Opcode::Drop,
+            Opcode::LoadLocal(0),
+        ]);
+        assert_eq!(
+            &*function.opcodes,
+            [
+                Opcode::Constant(0),
+                Opcode::StoreLocal(0),
+                Opcode::LoadLocal(0)
+            ]
+        );
+        assert_eq!(&*function.locations, [0..1, 2..3, 4..5]);
+    }
+
+    #[test]
+    fn remaps_jumps_around_removed_store() {
+        let function = optimized(&[
+            Opcode::JumpFalse(6),
+            Opcode::Constant(0),
+            Opcode::Push,
+            Opcode::StoreLocal(0),
+            Opcode::Drop,
+            Opcode::Jump(2),
+        ]);
+        // The jump to the end and the jump to "Push" move with the removal.
+        assert_eq!(
+            &*function.opcodes,
+            [
+                Opcode::JumpFalse(4),
+                Opcode::Constant(0),
+                Opcode::StoreLocal(0),
+                Opcode::Jump(2),
+            ]
+        );
+        assert_eq!(&*function.locations, [0..1, 1..2, 3..4, 5..6]);
+    }
+
+    #[test]
+    fn keeps_store_with_jump_inside() {
+        for target in [2, 3] {
+            let opcodes = [
+                Opcode::Constant(0),
+                Opcode::Push,
+                Opcode::StoreLocal(0),
+                Opcode::Drop,
+                Opcode::JumpTrue(target),
+            ];
+            let function = optimized(&opcodes);
+            assert_eq!(&*function.opcodes, opcodes);
+            assert_eq!(&*function.locations, [0..1, 1..2, 2..3, 3..4, 4..5]);
+        }
+    }
+
+    #[test]
+    fn threads_jumps_to_jumps() {
+        let function = optimized(&[
+            Opcode::JumpFalse(2),
+            Opcode::Jump(3),
+            Opcode::Jump(3),
+            Opcode::Jump(5),
+            Opcode::Constant(0),
+            Opcode::Return,
+        ]);
+        assert_eq!(
+            &*function.opcodes,
+            [
+                Opcode::JumpFalse(5),
+                Opcode::Jump(5),
+                Opcode::Jump(5),
+                Opcode::Jump(5),
+                Opcode::Constant(0),
+                Opcode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn threading_stops_on_cycles() {
+        let function = optimized(&[Opcode::Jump(0)]);
+        assert_eq!(&*function.opcodes, [Opcode::Jump(0)]);
+
+        let function = optimized(&[Opcode::Jump(1), Opcode::Jump(0)]);
+        assert!(function
+            .opcodes
+            .iter()
+            .all(|&opcode| matches!(opcode, Opcode::Jump(0 | 1))));
+    }
+
+    #[test]
+    fn empty_loop_is_kept() {
+        let program = crate::testing::build("loop {}");
+        let function = &program.modules()[0].functions[0];
+        // The loop jumps to itself forever.
+        assert!(function
+            .opcodes
+            .iter()
+            .enumerate()
+            .any(|(index, &opcode)| opcode == Opcode::Jump(index)));
+    }
+}
@@ -1,9 +1,12 @@
+use std::time::Instant;
+
 use crate::common::*;
+use crate::function::*;
 use crate::module::*;
 use crate::state::*;
 use crate::value::*;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
     Constant(usize),
     Addict,
@@ -22,6 +25,9 @@ pub enum Opcode {
     Less,
     GreaterEqual,
     LessEqual,
+    Negate,
+    Not,
+    BitNot,
     JumpFalse(usize),
     JumpTrue(usize),
     Jump(usize),
@@ -29,6 +35,51 @@ pub enum Opcode {
     LoadLocal(usize),
     Push,
     Drop,
+    Call(usize),
+    Return,
+    MakeArray(usize),
+    MakeMap(usize),
+    GetIndex,
+    SetIndex,
+}
+
+// The deadline is checked once per this many opcodes, reading the clock is
+// much slower than running an opcode.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+// Limits of running a program, "None" stands for no limit. Instructions are
+// taken from the budget as opcodes run, so what is left after an interrupted
+// run is zero and the budget has to be refilled before resuming. Budgets are
+// for embedders, the file runner and the REPL never apply one, so "loop {}"
+// runs there until the process is interrupted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    pub instructions: Option<u64>,
+    pub deadline: Option<Instant>,
+}
+
+impl Budget {
+    // Takes one instruction, nothing is taken when the run has to stop.
+    fn take(&mut self, steps: u64) -> Result<(), OperatorError> {
+        if let Some(deadline) = self.deadline {
+            if steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err((
+                    ErrorKind::BudgetExhausted,
+                    "Deadline is exceeded.".to_string(),
+                ));
+            }
+        }
+        if let Some(instructions) = &mut self.instructions {
+            if *instructions == 0 {
+                return Err((
+                    ErrorKind::BudgetExhausted,
+                    "Instruction budget is exhausted.".to_string(),
+                ));
+            }
+            *instructions -= 1;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -36,6 +87,12 @@ pub struct Program {
     modules: Vec<Module>,
 }
 
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Program {
     pub fn new() -> Self {
         Self {
@@ -47,24 +104,53 @@ impl Program {
         self.modules.push(module);
     }
 
-    fn binary<T: BinaryOperator>(&mut self, state: &mut State) -> Result<(), Error> {
-        match state.binary::<T>() {
-            Ok(_) => Ok(()),
-            Err(message) => Err(Error {
-                message,
-                location: self.modules[state.module_index()].functions[state.function_index()]
-                    .locations[state.opcode_index() - 1]
-                    .clone(),
-            }),
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    fn function(&self, state: &State) -> Option<&Function> {
+        self.modules
+            .get(state.module_index())?
+            .functions
+            .get(state.function_index())
+    }
+
+    fn error_at(
+        &self,
+        state: &State,
+        index: Option<usize>,
+        (kind, message): OperatorError,
+    ) -> Error {
+        let location = self
+            .function(state)
+            .zip(index)
+            .and_then(|(function, index)| function.locations.get(index))
+            .cloned()
+            .unwrap_or(0..0);
+        Error {
+            message,
+            location,
+            kind,
         }
     }
 
+    // Error at the opcode being run, which is already counted by the opcode
+    // index of the state.
+    fn error(&self, state: &State, error: OperatorError) -> Error {
+        self.error_at(state, state.opcode_index().checked_sub(1), error)
+    }
+
     fn jump(&mut self, state: &mut State, position: usize) {
         *state.opcode_index_mut() = position;
     }
 
-    fn jump_if(&mut self, state: &mut State, value: bool, position: usize) -> Result<(), Error> {
-        let value_from_stack = state.pop();
+    fn jump_if(
+        &mut self,
+        state: &mut State,
+        value: bool,
+        position: usize,
+    ) -> Result<(), OperatorError> {
+        let value_from_stack = state.pop()?;
         match value_from_stack {
             Value::Bool(value_from_stack) => {
                 if value_from_stack == value {
@@ -72,64 +158,350 @@ impl Program {
                 }
                 Ok(())
             }
-            _ => Err(Error {
-                message: format!("Expected bool value, but got {:?}.", value_from_stack,),
-                location: self.modules[state.module_index()].functions[state.function_index()]
-                    .locations[state.opcode_index()]
-                .clone(),
-            }),
+            _ => Err((
+                ErrorKind::Type,
+                format!(
+                    "Expected bool value, but got {}.",
+                    value_from_stack.type_name()
+                ),
+            )),
         }
     }
 
-    fn run_module(&mut self, index: usize) -> Result<Value, Error> {
-        let mut state = State::new(self.modules[index].functions[0].frame_size, index);
-        while let Some(&opcode) = self.modules[state.module_index()].functions
-            [state.function_index()]
-        .opcodes
-        .get(state.opcode_index())
-        {
-            *state.opcode_index_mut() += 1;
-            match opcode {
-                Opcode::Constant(index) => {
-                    state.push(self.modules[state.module_index()].constants[index])
-                }
-                Opcode::Addict => self.binary::<ArithmeticOrComparison<Addict>>(&mut state)?,
-                Opcode::Subtract => self.binary::<ArithmeticOrComparison<Subtract>>(&mut state)?,
-                Opcode::Multiply => self.binary::<ArithmeticOrComparison<Multiply>>(&mut state)?,
-                Opcode::Divide => self.binary::<ArithmeticOrComparison<Divide>>(&mut state)?,
-                Opcode::Modulo => self.binary::<ArithmeticOrComparison<Modulo>>(&mut state)?,
-                Opcode::And => self.binary::<Bitwise<And>>(&mut state)?,
-                Opcode::Or => self.binary::<Bitwise<Or>>(&mut state)?,
-                Opcode::Xor => self.binary::<Bitwise<Xor>>(&mut state)?,
-                Opcode::Shl => self.binary::<Bitwise<Shl>>(&mut state)?,
-                Opcode::Shr => self.binary::<Bitwise<Shr>>(&mut state)?,
-                Opcode::Equal => self.binary::<Equality<Equal>>(&mut state)?,
-                Opcode::NotEqual => self.binary::<Equality<NotEqual>>(&mut state)?,
-                Opcode::Greater => self.binary::<ArithmeticOrComparison<Greater>>(&mut state)?,
-                Opcode::Less => self.binary::<ArithmeticOrComparison<Less>>(&mut state)?,
-                Opcode::GreaterEqual => {
-                    self.binary::<ArithmeticOrComparison<GreaterEqual>>(&mut state)?
+    fn run_module(&mut self, index: usize, budget: &mut Budget) -> Result<Value, Error> {
+        let frame_size = self.modules[index]
+            .functions
+            .first()
+            .map_or(0, |function| function.frame_size);
+        let mut state = State::new(frame_size, index);
+        self.run_state_with_budget(&mut state, budget)
+    }
+
+    // Runs one opcode, returns false when the entry point returns.
+    fn step(&mut self, state: &mut State, opcode: Opcode) -> Result<bool, OperatorError> {
+        match opcode {
+            Opcode::Constant(index) => {
+                let value = self.modules[state.module_index()]
+                    .constants
+                    .get(index)
+                    .ok_or_else(|| {
+                        (
+                            ErrorKind::InvalidBytecode,
+                            format!("Constant {} doesn't exist.", index),
+                        )
+                    })?;
+                state.push(value.clone())?;
+            }
+            Opcode::Addict => state.binary::<StringOrArithmetic<Addict>>()?,
+            Opcode::Subtract => state.binary::<ArithmeticOrComparison<Subtract>>()?,
+            Opcode::Multiply => state.binary::<ArithmeticOrComparison<Multiply>>()?,
+            Opcode::Divide => state.binary::<ArithmeticOrComparison<Divide>>()?,
+            Opcode::Modulo => state.binary::<ArithmeticOrComparison<Modulo>>()?,
+            Opcode::And => state.binary::<Bitwise<And>>()?,
+            Opcode::Or => state.binary::<Bitwise<Or>>()?,
+            Opcode::Xor => state.binary::<Bitwise<Xor>>()?,
+            Opcode::Shl => state.binary::<Bitwise<Shl>>()?,
+            Opcode::Shr => state.binary::<Bitwise<Shr>>()?,
+            Opcode::Equal => state.binary::<Equality<Equal>>()?,
+            Opcode::NotEqual => state.binary::<Equality<NotEqual>>()?,
+            Opcode::Greater => state.binary::<StringOrArithmetic<Greater>>()?,
+            Opcode::Less => state.binary::<StringOrArithmetic<Less>>()?,
+            Opcode::GreaterEqual => state.binary::<StringOrArithmetic<GreaterEqual>>()?,
+            Opcode::LessEqual => state.binary::<StringOrArithmetic<LessEqual>>()?,
+            Opcode::Negate => state.unary::<Negate>()?,
+            Opcode::Not => state.unary::<Not>()?,
+            Opcode::BitNot => state.unary::<BitNot>()?,
+            Opcode::JumpFalse(position) => self.jump_if(state, false, position)?,
+            Opcode::JumpTrue(position) => self.jump_if(state, true, position)?,
+            Opcode::Jump(position) => self.jump(state, position),
+            Opcode::StoreLocal(position) => {
+                let value = state.pop()?;
+                *state.local_mut(position)? = value;
+            }
+            Opcode::LoadLocal(position) => state.push(state.local(position)?.clone())?,
+            Opcode::Push => state.push(state.peek()?)?,
+            Opcode::Drop => state.pop_drop()?,
+            Opcode::Call(index) => {
+                let function = self.modules[state.module_index()]
+                    .functions
+                    .get(index)
+                    .ok_or_else(|| {
+                        (
+                            ErrorKind::InvalidBytecode,
+                            format!("Function {} doesn't exist.", index),
+                        )
+                    })?;
+                state.call(
+                    function.frame_size,
+                    state.module_index(),
+                    index,
+                    function.params_count,
+                )?;
+            }
+            Opcode::MakeArray(count) => state.make_array(count)?,
+            Opcode::MakeMap(count) => state.make_map(count)?,
+            Opcode::GetIndex => state.get_index()?,
+            Opcode::SetIndex => state.set_index()?,
+            Opcode::Return => {
+                if state.depth() == 1 {
+                    return Ok(false);
                 }
-                Opcode::LessEqual => {
-                    self.binary::<ArithmeticOrComparison<LessEqual>>(&mut state)?
+                state.ret()?;
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn run_state(&mut self, state: &mut State) -> Result<Value, Error> {
+        self.run_state_with_budget(state, &mut Budget::default())
+    }
+
+    // Stops with a "BudgetExhausted" error at the opcode the budget ran out
+    // on, before running it. Running the same state again continues from that
+    // opcode, dropping the state aborts the run.
+    pub fn run_state_with_budget(
+        &mut self,
+        state: &mut State,
+        budget: &mut Budget,
+    ) -> Result<Value, Error> {
+        let mut steps: u64 = 0;
+        loop {
+            let opcode = match self.function(state) {
+                Some(function) => function.opcodes.get(state.opcode_index()).copied(),
+                None => {
+                    return Err(self.error(
+                        state,
+                        (
+                            ErrorKind::InvalidBytecode,
+                            format!("Function {} doesn't exist.", state.function_index()),
+                        ),
+                    ))
                 }
-                Opcode::JumpFalse(position) => self.jump_if(&mut state, false, position)?,
-                Opcode::JumpTrue(position) => self.jump_if(&mut state, true, position)?,
-                Opcode::Jump(position) => self.jump(&mut state, position),
-                Opcode::StoreLocal(position) => *state.local_mut(position) = state.pop(),
-                Opcode::LoadLocal(position) => state.push(*state.local(position)),
-                Opcode::Push => state.push(state.peek()),
-                Opcode::Drop => state.pop_drop(),
+            };
+            let Some(opcode) = opcode else {
+                break;
+            };
+            budget
+                .take(steps)
+                .map_err(|error| self.error_at(state, Some(state.opcode_index()), error))?;
+            steps += 1;
+            *state.opcode_index_mut() += 1;
+            if !self
+                .step(state, opcode)
+                .map_err(|error| self.error(state, error))?
+            {
+                break;
             }
         }
-        Ok(state.pop())
+
+        // Only the entry point may end without return, there is no caller
+        // to continue in.
+        if state.depth() > 1 {
+            return Err(self.error(
+                state,
+                (
+                    ErrorKind::InvalidBytecode,
+                    "Function ends without return.".to_string(),
+                ),
+            ));
+        }
+        state.pop().map_err(|error| self.error(state, error))
     }
 
     pub fn run(&mut self) -> Result<Value, Error> {
+        self.run_with_budget(&mut Budget::default())
+    }
+
+    // The budget is shared by all modules. An interrupted module can't be
+    // resumed from here, use "run_state_with_budget" for resumable runs.
+    pub fn run_with_budget(&mut self, budget: &mut Budget) -> Result<Value, Error> {
         let mut result = Value::Bool(false);
         for i in 0..self.modules.len() {
-            result = self.run_module(i)?;
+            result = self.run_module(i, budget)?;
         }
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Range, time::Duration};
+
+    use super::*;
+
+    const COUNTING: &str = "let i = 0; let s = 0; while i < 20 { s += i * i; i += 1 }; s";
+
+    fn entry_state(program: &Program) -> State {
+        State::new(program.modules()[0].functions[0].frame_size, 0)
+    }
+
+    fn instructions(count: u64) -> Budget {
+        Budget {
+            instructions: Some(count),
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn budget_exhausted_at_interrupted_opcode() {
+        let mut program = crate::testing::build(COUNTING);
+        let mut state = entry_state(&program);
+        let error = program
+            .run_state_with_budget(&mut state, &mut instructions(10))
+            .expect_err("run must be interrupted");
+        assert_eq!(error.kind, ErrorKind::BudgetExhausted);
+        assert_eq!(error.message, "Instruction budget is exhausted.");
+        assert_eq!(state.opcode_index(), 10);
+        let function = &program.modules()[0].functions[0];
+        assert_eq!(error.location, function.locations[10]);
+    }
+
+    #[test]
+    fn resumed_run_gives_same_result() {
+        let expected = crate::testing::build(COUNTING).run().ok().unwrap();
+
+        let mut program = crate::testing::build(COUNTING);
+        let mut state = entry_state(&program);
+        let mut interruptions = 0;
+        let value = loop {
+            match program.run_state_with_budget(&mut state, &mut instructions(7)) {
+                Ok(value) => break value,
+                Err(error) => {
+                    assert_eq!(error.kind, ErrorKind::BudgetExhausted);
+                    interruptions += 1;
+                }
+            }
+        };
+        assert!(interruptions > 10);
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn budget_is_shared_by_runs() {
+        let mut program = crate::testing::build("1 + 2");
+        let mut budget = instructions(1000);
+        assert!(program.run_with_budget(&mut budget).is_ok());
+        let left = budget.instructions.unwrap();
+        assert!(left < 1000);
+        assert!(program.run_with_budget(&mut budget).is_ok());
+        assert_eq!(1000 - budget.instructions.unwrap(), 2 * (1000 - left));
+    }
+
+    #[test]
+    fn deadline_stops_endless_loop() {
+        let mut program = crate::testing::build("loop {}");
+        let mut budget = Budget {
+            instructions: None,
+            deadline: Some(Instant::now() + Duration::from_millis(20)),
+        };
+        let error = program
+            .run_with_budget(&mut budget)
+            .expect_err("loop must be interrupted");
+        assert_eq!(error.kind, ErrorKind::BudgetExhausted);
+        assert_eq!(error.message, "Deadline is exceeded.");
+    }
+
+    #[test]
+    fn past_deadline_stops_before_first_opcode() {
+        let mut program = crate::testing::build(COUNTING);
+        let mut state = entry_state(&program);
+        let mut budget = Budget {
+            instructions: Some(5),
+            deadline: Some(Instant::now()),
+        };
+        let error = program
+            .run_state_with_budget(&mut state, &mut budget)
+            .expect_err("run must be interrupted");
+        assert_eq!(error.message, "Deadline is exceeded.");
+        assert_eq!(state.opcode_index(), 0);
+        assert_eq!(budget.instructions, Some(5));
+    }
+
+    // Function with the location of every opcode at "10 * position".
+    fn function(opcodes: &[Opcode], frame_size: usize) -> Function {
+        Function {
+            opcodes: opcodes.into(),
+            locations: (0..opcodes.len()).map(|i| i * 10..i * 10 + 1).collect(),
+            frame_size,
+            params_count: 0,
+        }
+    }
+
+    fn run_functions(functions: Vec<Function>) -> Result<Value, Error> {
+        let mut program = Program::new();
+        program.push(Module {
+            functions: functions.into(),
+            constants: Box::new([Value::Integer(1)]),
+        });
+        program.run()
+    }
+
+    fn run_error(functions: Vec<Function>) -> (ErrorKind, Range<usize>) {
+        let error = run_functions(functions).expect_err("program must fail");
+        (error.kind, error.location)
+    }
+
+    #[test]
+    fn empty_entry_point_underflows() {
+        assert_eq!(
+            run_error(vec![function(&[], 0)]),
+            (ErrorKind::StackUnderflow, 0..0)
+        );
+    }
+
+    #[test]
+    fn missing_constant_function_and_local() {
+        let cases = [
+            function(&[Opcode::Constant(0), Opcode::Constant(1)], 0),
+            function(&[Opcode::Constant(0), Opcode::Call(3)], 0),
+            function(&[Opcode::Constant(0), Opcode::LoadLocal(1)], 1),
+            function(&[Opcode::Constant(0), Opcode::StoreLocal(0)], 0),
+        ];
+        for case in cases {
+            assert_eq!(run_error(vec![case]), (ErrorKind::InvalidBytecode, 10..11));
+        }
+    }
+
+    #[test]
+    fn return_from_entry_point() {
+        let result = run_functions(vec![function(&[Opcode::Constant(0), Opcode::Return], 0)]);
+        assert_eq!(result.expect("entry point returns"), Value::Integer(1));
+        assert_eq!(
+            run_error(vec![function(&[Opcode::Return], 0)]),
+            (ErrorKind::StackUnderflow, 0..1)
+        );
+    }
+
+    #[test]
+    fn function_ending_without_return() {
+        assert_eq!(
+            run_error(vec![
+                function(&[Opcode::Call(1)], 0),
+                function(&[Opcode::Constant(0)], 0),
+            ]),
+            (ErrorKind::InvalidBytecode, 0..1)
+        );
+    }
+
+    #[test]
+    fn function_cant_pop_values_of_caller() {
+        assert_eq!(
+            run_error(vec![
+                function(&[Opcode::Constant(0), Opcode::Call(1)], 0),
+                function(&[Opcode::Drop, Opcode::Constant(0), Opcode::Return], 0),
+            ]),
+            (ErrorKind::StackUnderflow, 0..1)
+        );
+    }
+
+    #[test]
+    fn type_error_location() {
+        assert_eq!(
+            run_error(vec![function(
+                &[Opcode::Constant(0), Opcode::Not, Opcode::Return],
+                0
+            )]),
+            (ErrorKind::Type, 10..11)
+        );
+    }
+}
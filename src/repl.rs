@@ -1,8 +1,8 @@
 use std::io::Write;
 
 use crate::{
-    builder::Builder, expression::Expression, lexer::PeekableTokenIterator, program::Program,
-    value::Value,
+    diagnostic::{Diagnostic, Label},
+    session::{Session, SessionError},
 };
 
 enum ReplError {
@@ -10,71 +10,103 @@ enum ReplError {
     UnexpectedEnd,
 }
 
-fn repl_parse(code: &[u8]) -> Result<Expression, ReplError> {
-    let mut token_iterator = PeekableTokenIterator::new(code);
-    crate::parser::parse(&mut token_iterator).map_err(|error| {
-        if error.location.eq(0..0) {
-            ReplError::UnexpectedEnd
-        } else {
-            println!("Parser error: {}", error.message);
-            ReplError::SomeError
+fn repl_report(session: &Session, code: &str, error: SessionError) -> ReplError {
+    let source = format!("{}{}", session.source(), code);
+    let diagnostics = match error {
+        SessionError::Parse(errors) => {
+            // Unfinished code is completed on the next lines before reporting.
+            if errors.last().unwrap().location == (source.len()..source.len()) {
+                return ReplError::UnexpectedEnd;
+            }
+            errors
+                .into_iter()
+                .map(|error| Diagnostic::new(Label::Parse, error))
+                .collect()
         }
-    })
-}
-
-fn repl_build(code: &[u8], expression: Expression) -> Result<Program, ReplError> {
-    let mut builder = Builder::new(code);
-
-    crate::builder::build(&expression, &mut builder).map_err(|error| {
-        println!("Build error: {}", error.message);
-        ReplError::SomeError
-    })?;
-
-    Ok(builder.build())
+        SessionError::Build(error) => vec![Diagnostic::new(Label::Build, error)],
+        SessionError::Runtime(error) => vec![Diagnostic::new(Label::Runtime, error)
+            .with_note("variables keep the values they had before this input")],
+    };
+    for diagnostic in diagnostics {
+        print!("{}", diagnostic.render(&source, "<repl>"));
+    }
+    ReplError::SomeError
 }
 
-fn repl_run(mut program: Program) -> Result<Value, ReplError> {
-    program.run().map_err(|error| {
-        println!("Runtime error: {}", error.message);
-        ReplError::SomeError
-    })
+fn repl_warn(session: &mut Session, offset: usize, code: &str) {
+    let source = format!("{}{}", &session.source()[..offset], code);
+    for warning in session.take_warnings() {
+        let diagnostic =
+            Diagnostic::new(Label::Warning, warning).with_note("this fails when evaluated");
+        print!("{}", diagnostic.render(&source, "<repl>"));
+    }
 }
 
-fn repl_read_one_more_line(code: &mut String) {
+fn repl_read_one_more_line(code: &mut String) -> bool {
     std::io::stdout().flush().unwrap();
     let mut line = String::new();
-    std::io::stdin().read_line(&mut line).unwrap();
+    let count = std::io::stdin().read_line(&mut line).unwrap();
     code.push_str(line.as_str());
+    count != 0
 }
 
-fn repl_run_one_more_line(code: &mut String) -> Result<(), ReplError> {
-    repl_read_one_more_line(code);
-
-    let expression = repl_parse(code.as_bytes())?;
-
-    let program = repl_build(code.as_bytes(), expression)?;
-    //println!("{:#?}", program);
-    println!("{}", repl_run(program)?);
-    Ok(())
+// ":dis <code>" shows the bytecode of the code instead of running it, plain
+// ":dis" shows the functions declared so far.
+fn strip_disassemble_command(code: &str) -> Option<&str> {
+    let rest = code.trim_start().strip_prefix(":dis")?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest)
+    } else {
+        None
+    }
 }
 
-fn repl_iteration() {
+fn repl_iteration(session: &mut Session) -> bool {
     let mut code = String::new();
 
     print!("-> ");
+    if !repl_read_one_more_line(&mut code) {
+        println!();
+        return false;
+    }
+    let disassemble = match strip_disassemble_command(&code) {
+        Some(rest) if rest.trim().is_empty() => {
+            println!("{}", session.disassemble_functions().trim_end());
+            return true;
+        }
+        Some(rest) => {
+            code = rest.trim_start().to_string();
+            true
+        }
+        None => false,
+    };
+
     loop {
-        match repl_run_one_more_line(&mut code) {
-            Ok(_) => break,
-            Err(error) => match error {
-                ReplError::SomeError => break,
+        let offset = session.source().len();
+        let result = if disassemble {
+            session.disassemble(&code)
+        } else {
+            session.eval(&code).map(|value| value.to_string())
+        };
+        repl_warn(session, offset, &code);
+        match result {
+            Ok(text) => {
+                println!("{}", text.trim_end());
+                break true;
+            }
+            Err(error) => match repl_report(session, &code, error) {
+                ReplError::SomeError => break true,
                 ReplError::UnexpectedEnd => print!("-| "),
             },
         }
+        if !repl_read_one_more_line(&mut code) {
+            println!();
+            break false;
+        }
     }
 }
 
 pub fn run() {
-    loop {
-        repl_iteration()
-    }
+    let mut session = Session::new();
+    while repl_iteration(&mut session) {}
 }
@@ -0,0 +1,162 @@
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    rc::Rc,
+};
+
+use crate::{
+    builder::Builder,
+    common::Error,
+    diagnostic::{Diagnostic, Label},
+    expression::Expression,
+    lexer::PeekableTokenIterator,
+    program::Program,
+    state::State,
+    value::Value,
+};
+
+fn report(path: &str, code: &str, label: Label, error: Error) -> ExitCode {
+    eprint!("{}", Diagnostic::new(label, error).render(code, path));
+    ExitCode::FAILURE
+}
+
+// Operations found to fail while folding constants are kept, so they only
+// fail if the code runs.
+fn warn(path: &str, code: &str, error: Error) {
+    let diagnostic = Diagnostic::new(Label::Warning, error).with_note("this fails when evaluated");
+    eprint!("{}", diagnostic.render(code, path));
+}
+
+fn parse(path: &str, code: &str) -> Result<Expression, ExitCode> {
+    let mut token_iterator = PeekableTokenIterator::new(code.as_bytes());
+    let (expression, errors) = crate::parser::parse_recovering(&mut token_iterator);
+    if errors.is_empty() {
+        Ok(expression)
+    } else {
+        for error in errors {
+            report(path, code, Label::Parse, error);
+        }
+        Err(ExitCode::FAILURE)
+    }
+}
+
+// Script arguments are visible to the script as the "args" array of strings,
+// the first local of the entry point. It is declared at build time and set
+// before running, so compiled programs take arguments too.
+const ARGS_LOCAL: usize = 0;
+
+fn make_args(args: &[String]) -> Value {
+    let values = args
+        .iter()
+        .map(|arg| Value::String(arg.as_str().into()))
+        .collect();
+    Value::Array(Rc::new(RefCell::new(values)))
+}
+
+fn read_source(path: &str) -> Result<String, ExitCode> {
+    std::fs::read_to_string(path).map_err(|error| {
+        eprintln!("{}: Unable to read file: {}", path, error);
+        ExitCode::FAILURE
+    })
+}
+
+fn compile_source(path: &str, code: &str) -> Result<Program, ExitCode> {
+    let expression = parse(path, code)?;
+    let (expression, warnings) = crate::fold::fold(expression);
+    for warning in warnings {
+        warn(path, code, warning);
+    }
+
+    let mut builder = Builder::new();
+    builder.function_builder.new_local("args");
+    crate::builder::build(&expression, &mut builder)
+        .map_err(|error| report(path, code, Label::Build, error))?;
+    Ok(builder.build())
+}
+
+fn load_bytecode(path: &str) -> Result<(Program, String), ExitCode> {
+    let bytes = std::fs::read(path).map_err(|error| {
+        eprintln!("{}: Unable to read file: {}", path, error);
+        ExitCode::FAILURE
+    })?;
+    crate::bytecode::load(&bytes).map_err(|message| {
+        eprintln!("{}: {}", path, message);
+        ExitCode::FAILURE
+    })
+}
+
+fn run_program(path: &str, code: &str, mut program: Program, args: &[String]) -> ExitCode {
+    if let Err(error) = crate::verifier::verify(&program) {
+        eprintln!("{}: {}", path, error);
+        return ExitCode::FAILURE;
+    }
+    let frame_size = match program
+        .modules()
+        .first()
+        .and_then(|module| module.functions.first())
+    {
+        Some(function) if function.frame_size > ARGS_LOCAL => function.frame_size,
+        _ => {
+            eprintln!("{}: Program has no script entry point.", path);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut state = State::new(frame_size, 0);
+    if let Ok(local) = state.local_mut(ARGS_LOCAL) {
+        *local = make_args(args);
+    }
+
+    match program.run_state(&mut state) {
+        Ok(value) => {
+            println!("{}", value);
+            ExitCode::SUCCESS
+        }
+        Err(error) => report(path, code, Label::Runtime, error),
+    }
+}
+
+// Runs a script from source or from a ".irisc" file made by "compile".
+pub fn run(path: &str, args: &[String]) -> ExitCode {
+    let (program, code) = if path.ends_with(".irisc") {
+        match load_bytecode(path) {
+            Ok(loaded) => loaded,
+            Err(code) => return code,
+        }
+    } else {
+        let code = match read_source(path) {
+            Ok(code) => code,
+            Err(code) => return code,
+        };
+        match compile_source(path, &code) {
+            Ok(program) => (program, code),
+            Err(code) => return code,
+        }
+    };
+    run_program(path, &code, program, args)
+}
+
+// Saves the compiled script to "output", by default next to the script with
+// the ".irisc" extension.
+pub fn compile(path: &str, output: Option<&str>) -> ExitCode {
+    let code = match read_source(path) {
+        Ok(code) => code,
+        Err(code) => return code,
+    };
+    let program = match compile_source(path, &code) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(path).with_extension("irisc"),
+    };
+    match std::fs::write(&output, crate::bytecode::save(&program, &code)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}: Unable to write file: {}", output.display(), error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{
+    builder::Builder,
+    common::Error,
+    lexer::PeekableTokenIterator,
+    program::Program,
+    state::{Frame, Limits, State},
+    value::Value,
+};
+
+pub enum SessionError {
+    Parse(Vec<Error>),
+    Build(Error),
+    Runtime(Error),
+}
+
+// Evaluates chunks of code one after another in the same module, so that
+// variables, functions and constants of previous chunks stay available.
+pub struct Session {
+    source: String,
+    builder: Builder,
+    frame: Frame,
+    limits: Limits,
+    warnings: Vec<Error>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            source: String::new(),
+            builder: Builder::new(),
+            frame: Frame::new(0),
+            limits: Limits::default(),
+            warnings: Vec::new(),
+        }
+    }
+
+    // Limits every following chunk is run with.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Warnings of the last evaluated or disassembled chunk. They are kept
+    // when the chunk fails at runtime, so locations may point past "source".
+    pub fn take_warnings(&mut self) -> Vec<Error> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    // All evaluated code, error locations are offsets into it.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn eval(&mut self, code: &str) -> Result<Value, SessionError> {
+        let offset = self.source.len();
+        self.source.push_str(code);
+        let result = self.eval_from(offset);
+        if result.is_err() {
+            self.source.truncate(offset);
+        }
+        result
+    }
+
+    // Shows the bytecode of all functions declared so far.
+    pub fn disassemble_functions(&self) -> String {
+        let program = self.builder.clone().build_entry();
+        crate::disassembler::disassemble(&program, &self.source)
+    }
+
+    // Shows the bytecode the code compiles to without running it, functions
+    // declared by the code are not kept.
+    pub fn disassemble(&mut self, code: &str) -> Result<String, SessionError> {
+        let offset = self.source.len();
+        self.source.push_str(code);
+        self.warnings.clear();
+        let result = self.compile_from(offset).map(|(program, _, warnings)| {
+            self.warnings = warnings;
+            crate::disassembler::disassemble(&program, &self.source)
+        });
+        self.source.truncate(offset);
+        result
+    }
+
+    // Works on a copy of the builder, so a failed chunk leaves the session
+    // untouched.
+    fn compile_from(&self, offset: usize) -> Result<(Program, Builder, Vec<Error>), SessionError> {
+        let mut it = PeekableTokenIterator::with_offset(self.source.as_bytes(), offset);
+        let (expression, errors) = crate::parser::parse_recovering(&mut it);
+        if !errors.is_empty() {
+            return Err(SessionError::Parse(errors));
+        }
+
+        let (expression, warnings) = crate::fold::fold(expression);
+
+        let mut builder = self.builder.clone();
+        crate::builder::build(&expression, &mut builder).map_err(SessionError::Build)?;
+        Ok((builder.build_entry(), builder, warnings))
+    }
+
+    fn eval_from(&mut self, offset: usize) -> Result<Value, SessionError> {
+        self.warnings.clear();
+        let (mut program, builder, warnings) = self.compile_from(offset)?;
+        self.warnings = warnings;
+
+        let frame_size = builder.function_builder.frame_size();
+        let mut state = State::with_frame(self.frame.clone().resize(frame_size), 0);
+        state.set_limits(self.limits);
+        let value = program
+            .run_state(&mut state)
+            .map_err(SessionError::Runtime)?;
+
+        self.builder = builder;
+        self.frame = state.into_frame();
+        Ok(value)
+    }
+}
@@ -1,80 +1,256 @@
-use crate::value::*;
+use std::{cell::RefCell, collections::HashMap, mem::size_of, rc::Rc};
 
+use crate::{common::ErrorKind, value::*};
+
+#[derive(Clone)]
 pub struct Frame(Box<[Value]>);
 
 impl Frame {
     pub fn new(size: usize) -> Self {
         Self(vec![Value::Bool(false); size].into_boxed_slice())
     }
+
+    pub fn resize(self, size: usize) -> Self {
+        let mut values = self.0.into_vec();
+        values.resize(size.max(values.len()), Value::Bool(false));
+        Self(values.into_boxed_slice())
+    }
+}
+
+fn local_out_of_range(index: usize) -> OperatorError {
+    (
+        ErrorKind::InvalidBytecode,
+        format!("Local {} is outside of the frame.", index),
+    )
+}
+
+// Limits of the resources a run may use, "None" stands for no limit. Stack
+// depth counts values of all functions being called. Memory counts bytes of
+// strings, arrays and maps created by the run, values are not subtracted
+// when freed, so it limits the total allocated rather than the live memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub stack_depth: Option<usize>,
+    pub call_depth: Option<usize>,
+    pub memory: Option<usize>,
+}
+
+// Every state starts with these, so runaway scripts end with an error before
+// they exhaust the memory of the host.
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            stack_depth: Some(1 << 20),
+            call_depth: Some(10_000),
+            memory: Some(1 << 30),
+        }
+    }
+}
+
+impl Limits {
+    pub fn unlimited() -> Self {
+        Self {
+            stack_depth: None,
+            call_depth: None,
+            memory: None,
+        }
+    }
+}
+
+// Bytes a heap value holds by itself, values it contains are counted when
+// they are created.
+fn heap_size(value: &Value) -> usize {
+    match value {
+        Value::String(value) => value.len(),
+        Value::Array(array) => array.borrow().len() * size_of::<Value>(),
+        Value::Map(map) => map.borrow().len() * size_of::<(Value, Value)>(),
+        _ => 0,
+    }
 }
 
 pub struct State {
+    limits: Limits,
+    allocated: usize,
     stack: Vec<Value>,
     frames: Vec<Frame>,
     module_indexes: Vec<usize>,
     function_indexes: Vec<usize>,
     opcode_indexes: Vec<usize>,
-    params_counts: Vec<usize>,
+    stack_bases: Vec<usize>,
 }
 
 impl State {
     pub fn new(frame_size: usize, module_index: usize) -> Self {
+        Self::with_frame(Frame::new(frame_size), module_index)
+    }
+
+    pub fn with_frame(frame: Frame, module_index: usize) -> Self {
         Self {
+            limits: Limits::default(),
+            allocated: 0,
             stack: Vec::new(),
-            frames: vec![Frame::new(frame_size)],
+            frames: vec![frame],
             module_indexes: vec![module_index],
             function_indexes: vec![0],
             opcode_indexes: vec![0],
-            params_counts: vec![0],
+            stack_bases: vec![0],
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // Bytes of heap values created so far, see "Limits".
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    fn allocate(&mut self, size: usize) -> Result<(), OperatorError> {
+        self.allocated = self.allocated.saturating_add(size);
+        match self.limits.memory {
+            Some(memory) if self.allocated > memory => Err((
+                ErrorKind::OutOfMemory,
+                format!("Allocated memory exceeds limit of {} bytes.", memory),
+            )),
+            _ => Ok(()),
         }
     }
 
-    pub fn local(&self, index: usize) -> &Value {
-        self.frames.last().unwrap().0.get(index).unwrap()
+    pub fn into_frame(self) -> Frame {
+        self.frames.into_iter().next().unwrap()
+    }
+
+    pub fn local(&self, index: usize) -> Result<&Value, OperatorError> {
+        self.frames
+            .last()
+            .unwrap()
+            .0
+            .get(index)
+            .ok_or_else(|| local_out_of_range(index))
+    }
+
+    pub fn local_mut(&mut self, index: usize) -> Result<&mut Value, OperatorError> {
+        self.frames
+            .last_mut()
+            .unwrap()
+            .0
+            .get_mut(index)
+            .ok_or_else(|| local_out_of_range(index))
+    }
+
+    pub fn push(&mut self, value: Value) -> Result<(), OperatorError> {
+        match self.limits.stack_depth {
+            Some(stack_depth) if self.stack.len() >= stack_depth => Err((
+                ErrorKind::StackOverflow,
+                format!("Stack depth exceeds limit of {}.", stack_depth),
+            )),
+            _ => {
+                self.stack.push(value);
+                Ok(())
+            }
+        }
     }
 
-    pub fn local_mut(&mut self, index: usize) -> &mut Value {
-        self.frames.last_mut().unwrap().0.get_mut(index).unwrap()
+    // Values of the current function, values below belong to the callers.
+    fn available(&self) -> usize {
+        self.stack.len() - self.stack_base()
     }
 
-    pub fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    fn check_available(&self, count: usize) -> Result<(), OperatorError> {
+        if count > self.available() {
+            Err((
+                ErrorKind::StackUnderflow,
+                format!(
+                    "Opcode needs {} values, but the stack has {}.",
+                    count,
+                    self.available()
+                ),
+            ))
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+    pub fn pop(&mut self) -> Result<Value, OperatorError> {
+        self.check_available(1)?;
+        Ok(self.stack.pop().unwrap())
     }
 
-    pub fn peek(&self) -> Value {
-        *self.stack.last().unwrap()
+    pub fn peek(&self) -> Result<Value, OperatorError> {
+        self.check_available(1)?;
+        Ok(self.stack.last().unwrap().clone())
     }
 
-    pub fn pop_drop(&mut self) {
-        self.stack.pop();
+    pub fn pop_drop(&mut self) -> Result<(), OperatorError> {
+        self.pop().map(|_| ())
     }
 
-    pub fn call(&mut self, frame_size: usize, module_index: usize, function_index: usize, params_count: usize) {
-        self.frames.push(Frame::new(frame_size));
+    pub fn call(
+        &mut self,
+        frame_size: usize,
+        module_index: usize,
+        function_index: usize,
+        params_count: usize,
+    ) -> Result<(), OperatorError> {
+        self.check_available(params_count)?;
+        if params_count > frame_size {
+            return Err((
+                ErrorKind::InvalidBytecode,
+                format!(
+                    "{} params don't fit in frame of size {}.",
+                    params_count, frame_size
+                ),
+            ));
+        }
+        if let Some(call_depth) = self.limits.call_depth {
+            if self.depth() >= call_depth {
+                return Err((
+                    ErrorKind::StackOverflow,
+                    format!("Call depth exceeds limit of {}.", call_depth),
+                ));
+            }
+        }
+        let stack_base = self.stack.len() - params_count;
+        let mut frame = Frame::new(frame_size);
+        frame.0[..params_count].clone_from_slice(&self.stack[stack_base..]);
+        self.stack.truncate(stack_base);
+
+        self.frames.push(frame);
         self.module_indexes.push(module_index);
         self.function_indexes.push(function_index);
         self.opcode_indexes.push(0);
-        self.params_counts.push(params_count);
+        self.stack_bases.push(stack_base);
+        Ok(())
     }
 
-    pub fn params_count(&self) -> usize {
-        *self.params_counts.last().unwrap()
+    pub fn stack_base(&self) -> usize {
+        *self.stack_bases.last().unwrap()
     }
 
-    pub fn ret(&mut self) {
-        let result = self.pop();
-        self.stack.resize(self.stack.len() - self.params_count(), Value::Bool(false));
-        self.push(result);
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Returns from the current function to its caller, the entry point has
+    // no caller to return to.
+    pub fn ret(&mut self) -> Result<(), OperatorError> {
+        if self.depth() == 1 {
+            return Err((
+                ErrorKind::InvalidBytecode,
+                "Unable to return from the entry point.".to_string(),
+            ));
+        }
+        let result = self.pop()?;
+        self.stack.truncate(self.stack_base());
+        self.push(result)?;
 
         self.frames.pop();
         self.module_indexes.pop();
         self.function_indexes.pop();
         self.opcode_indexes.pop();
-        self.params_counts.pop();
+        self.stack_bases.pop();
+        Ok(())
     }
 
     pub fn function_index(&self) -> usize {
@@ -93,10 +269,157 @@ impl State {
         self.opcode_indexes.last_mut().unwrap()
     }
 
-    pub fn binary<T: BinaryOperator>(&mut self) -> Result<(), String> {
-        let rhs = self.pop();
-        let lhs = self.pop();
-        self.push(T::eval(lhs, rhs)?);
-        Ok(())
+    pub fn unary<T: UnaryOperator>(&mut self) -> Result<(), OperatorError> {
+        let value = self.pop()?;
+        self.push(T::eval(value)?)
+    }
+
+    pub fn make_array(&mut self, count: usize) -> Result<(), OperatorError> {
+        self.check_available(count)?;
+        self.allocate(count * size_of::<Value>())?;
+        let values = self.stack.split_off(self.stack.len() - count);
+        self.push(Value::Array(Rc::new(RefCell::new(values))))
+    }
+
+    // Heap values are hashed by identity, so mutating them never changes a key.
+    #[allow(clippy::mutable_key_type)]
+    pub fn make_map(&mut self, count: usize) -> Result<(), OperatorError> {
+        self.check_available(count.saturating_mul(2))?;
+        self.allocate(count * size_of::<(Value, Value)>())?;
+        let mut values = self
+            .stack
+            .split_off(self.stack.len() - count * 2)
+            .into_iter();
+        let mut map = HashMap::with_capacity(count);
+        while let (Some(key), Some(value)) = (values.next(), values.next()) {
+            check_key(&key)?;
+            map.insert(key, value);
+        }
+        self.push(Value::Map(Rc::new(RefCell::new(map))))
+    }
+
+    pub fn get_index(&mut self) -> Result<(), OperatorError> {
+        self.check_available(2)?;
+        let index = self.pop()?;
+        let container = self.pop()?;
+        self.push(get_index(&container, &index)?)
+    }
+
+    pub fn set_index(&mut self) -> Result<(), OperatorError> {
+        self.check_available(3)?;
+        let value = self.pop()?;
+        let index = self.pop()?;
+        let container = self.pop()?;
+        // Assigning a new key grows the map.
+        let size = heap_size(&container);
+        set_index(&container, &index, value.clone())?;
+        self.allocate(heap_size(&container).saturating_sub(size))?;
+        self.push(value)
+    }
+
+    pub fn binary<T: BinaryOperator>(&mut self) -> Result<(), OperatorError> {
+        self.check_available(2)?;
+        let rhs = self.pop()?;
+        let lhs = self.pop()?;
+        let result = T::eval(lhs, rhs)?;
+        self.allocate(heap_size(&result))?;
+        self.push(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        session::{Session, SessionError},
+        testing::runtime_error,
+    };
+
+    fn limited_error(limits: Limits, code: &str) -> (ErrorKind, String) {
+        let mut session = Session::new();
+        session.set_limits(limits);
+        match session.eval(code) {
+            Err(SessionError::Runtime(error)) => (error.kind, error.message),
+            _ => panic!("{:?} must fail at runtime", code),
+        }
+    }
+
+    fn memory(bytes: usize) -> Limits {
+        Limits {
+            memory: Some(bytes),
+            ..Limits::unlimited()
+        }
+    }
+
+    #[test]
+    fn stack_depth_limit() {
+        let limits = Limits {
+            stack_depth: Some(100),
+            ..Limits::unlimited()
+        };
+        let code = format!("[{}]", vec!["1"; 101].join(", "));
+        assert_eq!(
+            limited_error(limits, &code),
+            (
+                ErrorKind::StackOverflow,
+                "Stack depth exceeds limit of 100.".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn call_depth_limit() {
+        let limits = Limits {
+            call_depth: Some(50),
+            ..Limits::unlimited()
+        };
+        assert_eq!(
+            limited_error(limits, "fn f(n) { f(n + 1) } f(0)"),
+            (
+                ErrorKind::StackOverflow,
+                "Call depth exceeds limit of 50.".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn default_limits_stop_infinite_recursion() {
+        let error = runtime_error("fn f() { f() } f()");
+        assert_eq!(error.kind, ErrorKind::StackOverflow);
+    }
+
+    #[test]
+    fn memory_limit_on_string_doubling() {
+        let (kind, _) = limited_error(memory(10_000), "let s = \"ab\"; while true { s = s + s; }");
+        assert_eq!(kind, ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn memory_limit_on_array_growth() {
+        let (kind, _) = limited_error(memory(10_000), "let a = [1]; while true { a = [a, a, a]; }");
+        assert_eq!(kind, ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn memory_limit_on_map_insertion() {
+        let (kind, message) = limited_error(
+            memory(10_000),
+            "let m = {}; let i = 0; while true { m[i] = i; i += 1; }",
+        );
+        assert_eq!(kind, ErrorKind::OutOfMemory);
+        assert_eq!(message, "Allocated memory exceeds limit of 10000 bytes.");
+    }
+
+    #[test]
+    fn limits_dont_affect_small_programs() {
+        let mut session = Session::new();
+        session.set_limits(Limits {
+            stack_depth: Some(100),
+            call_depth: Some(50),
+            memory: Some(10_000),
+        });
+        assert!(session
+            .eval("fn f(n) { if n == 0 { 0 } else { f(n - 1) } } [f(40), \"ab\" + \"cd\"]")
+            .is_ok());
     }
 }
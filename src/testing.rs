@@ -0,0 +1,81 @@
+// Helpers shared by the unit tests of the crate, they panic with the error
+// message when code doesn't get as far as the test expects.
+use crate::{
+    builder::{self, Builder},
+    common::Error,
+    expression::Expression,
+    lexer::PeekableTokenIterator,
+    program::Program,
+    session::{Session, SessionError},
+    value::Value,
+};
+
+pub fn parse(code: &str) -> Expression {
+    let mut it = PeekableTokenIterator::new(code.as_bytes());
+    match crate::parser::parse(&mut it) {
+        Ok(expression) => expression,
+        Err(error) => panic!("{:?} doesn't parse: {}", code, error.message),
+    }
+}
+
+// Unwraps the list of code made of a single expression.
+pub fn single(expression: Expression) -> Expression {
+    match expression {
+        Expression::ExprList(mut list) if list.exprs.len() == 1 => list.exprs.remove(0),
+        _ => panic!("code must be a single expression"),
+    }
+}
+
+pub fn build_into(code: &str, builder: &mut Builder) -> Result<(), Error> {
+    builder::build(&parse(code), builder)
+}
+
+pub fn build(code: &str) -> Program {
+    let mut builder = Builder::new();
+    if let Err(error) = build_into(code, &mut builder) {
+        panic!("{:?} doesn't build: {}", code, error.message);
+    }
+    builder.build()
+}
+
+pub fn eval_in(session: &mut Session, code: &str) -> Value {
+    match session.eval(code) {
+        Ok(value) => value,
+        Err(SessionError::Parse(errors)) => {
+            panic!("{:?} doesn't parse: {}", code, errors[0].message)
+        }
+        Err(SessionError::Build(error) | SessionError::Runtime(error)) => {
+            panic!("{:?} fails: {}", code, error.message)
+        }
+    }
+}
+
+pub fn eval(code: &str) -> Value {
+    eval_in(&mut Session::new(), code)
+}
+
+// Result shown the way the REPL shows it, so tests can compare any value.
+pub fn eval_text(code: &str) -> String {
+    eval(code).to_string()
+}
+
+pub fn parse_errors(code: &str) -> Vec<Error> {
+    match Session::new().eval(code) {
+        Err(SessionError::Parse(errors)) => errors,
+        _ => panic!("{:?} must fail to parse", code),
+    }
+}
+
+pub fn build_error(code: &str) -> Error {
+    match Session::new().eval(code) {
+        Err(SessionError::Build(error)) => error,
+        _ => panic!("{:?} must fail to build", code),
+    }
+}
+
+pub fn runtime_error(code: &str) -> Error {
+    match Session::new().eval(code) {
+        Err(SessionError::Runtime(error)) => error,
+        _ => panic!("{:?} must fail at runtime", code),
+    }
+}
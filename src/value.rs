@@ -1,22 +1,123 @@
 use std::{
-    fmt::{Display, Formatter},
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{Debug, Display, Formatter},
     hash::{Hash, Hasher},
     marker::PhantomData,
+    rc::Rc,
 };
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+use crate::common::ErrorKind;
+
+#[derive(Clone)]
 pub enum Value {
     Bool(bool),
     Integer(i64),
     Real(f64),
+    String(Rc<str>),
+    Array(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<HashMap<Value, Value>>>),
+}
+
+impl Value {
+    // Name of the type for error messages, values themselves may be large or
+    // contain themselves.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Integer(_) => "integer",
+            Value::Real(_) => "real",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+        }
+    }
+}
+
+// Writes the value, "visited" holds the arrays and maps being written, so a
+// container that contains itself is shown as "[...]" or "{...}".
+fn write_value(
+    f: &mut Formatter<'_>,
+    value: &Value,
+    quote_strings: bool,
+    visited: &mut Vec<*const ()>,
+) -> std::fmt::Result {
+    match value {
+        Value::Bool(value) => write!(f, "{}", value),
+        Value::Integer(value) => write!(f, "{}", value),
+        Value::Real(value) => write!(f, "{}", value),
+        Value::String(value) if quote_strings => write!(f, "{:?}", value),
+        Value::String(value) => write!(f, "{}", value),
+        Value::Array(array) => {
+            let pointer = Rc::as_ptr(array) as *const ();
+            if visited.contains(&pointer) {
+                return write!(f, "[...]");
+            }
+            visited.push(pointer);
+            write!(f, "[")?;
+            for (i, value) in array.borrow().iter().enumerate() {
+                if i != 0 {
+                    write!(f, ", ")?;
+                }
+                write_value(f, value, true, visited)?;
+            }
+            visited.pop();
+            write!(f, "]")
+        }
+        Value::Map(map) => {
+            let pointer = Rc::as_ptr(map) as *const ();
+            if visited.contains(&pointer) {
+                return write!(f, "{{...}}");
+            }
+            visited.push(pointer);
+            write!(f, "{{")?;
+            for (i, (key, value)) in map.borrow().iter().enumerate() {
+                if i != 0 {
+                    write!(f, ", ")?;
+                }
+                write_value(f, key, true, visited)?;
+                write!(f, ": ")?;
+                write_value(f, value, true, visited)?;
+            }
+            visited.pop();
+            write!(f, "}}")
+        }
+    }
+}
+
+// Shows a value the way it is written in code, strings are quoted.
+pub struct Element<'a>(pub &'a Value);
+
+impl Display for Element<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write_value(f, self.0, true, &mut Vec::new())
+    }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match *self {
-            Value::Bool(value) => write!(f, "{}", value),
-            Value::Integer(value) => write!(f, "{}", value),
-            Value::Real(value) => write!(f, "{}", value),
+        write_value(f, self, false, &mut Vec::new())
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({})", self.type_name(), Element(self))
+    }
+}
+
+// Consistent with `Hash`: reals compare bitwise and heap values with interior
+// mutability compare by identity.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(lhs), Value::Bool(rhs)) => lhs == rhs,
+            (Value::Integer(lhs), Value::Integer(rhs)) => lhs == rhs,
+            (Value::Real(lhs), Value::Real(rhs)) => lhs.to_bits() == rhs.to_bits(),
+            (Value::String(lhs), Value::String(rhs)) => lhs == rhs,
+            (Value::Array(lhs), Value::Array(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Value::Map(lhs), Value::Map(rhs)) => Rc::ptr_eq(lhs, rhs),
+            _ => false,
         }
     }
 }
@@ -26,20 +127,29 @@ impl Eq for Value {}
 impl Hash for Value {
     fn hash<H: Hasher>(&self, state: &mut H) {
         std::mem::discriminant(self).hash(state);
-        match *self {
+        match self {
             Value::Bool(value) => value.hash(state),
             Value::Integer(value) => value.hash(state),
             Value::Real(value) => value.to_bits().hash(state),
+            Value::String(value) => value.hash(state),
+            Value::Array(value) => Rc::as_ptr(value).hash(state),
+            Value::Map(value) => Rc::as_ptr(value).hash(state),
         }
     }
 }
 
-pub type OperatorResult = Result<Value, String>;
+// Kind and message of the error an operator fails with.
+pub type OperatorError = (ErrorKind, String);
+pub type OperatorResult = Result<Value, OperatorError>;
 
 pub trait BinaryOperator {
     fn eval(lhs: Value, rhs: Value) -> OperatorResult;
 }
 
+pub trait UnaryOperator {
+    fn eval(value: Value) -> OperatorResult;
+}
+
 pub trait IntOperator {
     fn eval(lhs: i64, rhs: i64) -> OperatorResult;
 }
@@ -52,8 +162,12 @@ pub trait BoolOperator {
     fn eval(lhs: bool, rhs: bool) -> OperatorResult;
 }
 
+pub trait StringOperator {
+    fn eval(lhs: &str, rhs: &str) -> OperatorResult;
+}
+
 macro_rules! generate_implement {
-    ($trait_name:ident, $struct_name:ident, $value_type:ident, $result_type:ident, $op:tt) => {
+    ($trait_name:ident, $struct_name:ident, $value_type:ty, $result_type:ident, $op:tt) => {
         impl $trait_name for $struct_name {
             fn eval(lhs: $value_type, rhs: $value_type) -> OperatorResult {
                 Ok(Value::$result_type(lhs $op rhs))
@@ -70,17 +184,23 @@ generate_implement!(IntOperator, And, i64, Integer, &);
 generate_implement!(IntOperator, Or, i64, Integer, |);
 generate_implement!(IntOperator, Xor, i64, Integer, ^);
 
-fn correct_rhs_for_shirt(rhs: i64) -> Result<u32, String> {
+fn correct_rhs_for_shirt(rhs: i64) -> Result<u32, OperatorError> {
     if rhs < 0 {
-        Err(format!(
-            "Unable to use negative ({}) value as right hand side in shift operation.",
-            rhs
+        Err((
+            ErrorKind::Arithmetic,
+            format!(
+                "Unable to use negative ({}) value as right hand side in shift operation.",
+                rhs
+            ),
         ))
     } else {
         if rhs as usize > u32::MAX as usize {
-            Err(format!(
-                "Unable to use so big ({}) value as right hand side in shift operation.",
-                rhs
+            Err((
+                ErrorKind::Arithmetic,
+                format!(
+                    "Unable to use so big ({}) value as right hand side in shift operation.",
+                    rhs
+                ),
             ))
         } else {
             Ok(rhs as u32)
@@ -130,12 +250,21 @@ macro_rules! generate_implement_safe_int {
 }
 
 generate_implement_safe_int!(Addict, wrapping_add);
+
+impl StringOperator for Addict {
+    fn eval(lhs: &str, rhs: &str) -> OperatorResult {
+        let mut result = String::with_capacity(lhs.len() + rhs.len());
+        result.push_str(lhs);
+        result.push_str(rhs);
+        Ok(Value::String(result.into()))
+    }
+}
 generate_implement_safe_int!(Subtract, wrapping_sub);
 generate_implement_safe_int!(Multiply, wrapping_mul);
 
-fn check_for_zero(value: i64) -> Result<(), String> {
+fn check_for_zero(value: i64) -> Result<(), OperatorError> {
     if value == 0 {
-        Err(format!("Dividing by zero."))
+        Err((ErrorKind::Arithmetic, "Dividing by zero.".to_string()))
     } else {
         Ok(())
     }
@@ -153,7 +282,7 @@ pub struct Modulo;
 impl IntOperator for Modulo {
     fn eval(lhs: i64, rhs: i64) -> OperatorResult {
         check_for_zero(rhs)?;
-        Ok(Value::Integer(lhs % rhs))
+        Ok(Value::Integer(lhs.wrapping_rem(rhs)))
     }
 }
 
@@ -170,6 +299,10 @@ generate_implement!(IntOperator, Greater, i64, Bool, >);
 generate_implement!(RealOperator, Greater, f64, Bool, >);
 generate_implement!(IntOperator, GreaterEqual, i64, Bool, >=);
 generate_implement!(RealOperator, GreaterEqual, f64, Bool, >=);
+generate_implement!(StringOperator, Less, &str, Bool, <);
+generate_implement!(StringOperator, LessEqual, &str, Bool, <=);
+generate_implement!(StringOperator, Greater, &str, Bool, >);
+generate_implement!(StringOperator, GreaterEqual, &str, Bool, >=);
 
 pub struct Equal;
 pub struct NotEqual;
@@ -180,11 +313,17 @@ generate_implement!(RealOperator, Equal, f64, Bool, ==);
 generate_implement!(BoolOperator, NotEqual, bool, Bool, !=);
 generate_implement!(IntOperator, NotEqual, i64, Bool, !=);
 generate_implement!(RealOperator, NotEqual, f64, Bool, !=);
+generate_implement!(StringOperator, Equal, &str, Bool, ==);
+generate_implement!(StringOperator, NotEqual, &str, Bool, !=);
 
 fn unable_to_use(lhs: Value, rhs: Value) -> OperatorResult {
-    Err(format!(
-        "Can't use {:?} and {:?} in this binary operation.",
-        lhs, rhs,
+    Err((
+        ErrorKind::Type,
+        format!(
+            "Can't use {} and {} in this binary operation.",
+            lhs.type_name(),
+            rhs.type_name(),
+        ),
     ))
 }
 
@@ -194,42 +333,250 @@ pub struct ArithmeticOrComparison<T: IntOperator + RealOperator> {
 
 impl<T: IntOperator + RealOperator> BinaryOperator for ArithmeticOrComparison<T> {
     fn eval(lhs: Value, rhs: Value) -> OperatorResult {
-        match (lhs, rhs) {
-            (Value::Integer(lhs), Value::Integer(rhs)) => <T as IntOperator>::eval(lhs, rhs),
-            (Value::Integer(lhs), Value::Real(rhs)) => <T as RealOperator>::eval(lhs as f64, rhs),
-            (Value::Real(lhs), Value::Integer(rhs)) => <T as RealOperator>::eval(lhs, rhs as f64),
-            (Value::Real(lhs), Value::Real(rhs)) => <T as RealOperator>::eval(lhs, rhs),
+        match (&lhs, &rhs) {
+            (Value::Integer(lhs), Value::Integer(rhs)) => <T as IntOperator>::eval(*lhs, *rhs),
+            (Value::Integer(lhs), Value::Real(rhs)) => <T as RealOperator>::eval(*lhs as f64, *rhs),
+            (Value::Real(lhs), Value::Integer(rhs)) => <T as RealOperator>::eval(*lhs, *rhs as f64),
+            (Value::Real(lhs), Value::Real(rhs)) => <T as RealOperator>::eval(*lhs, *rhs),
             _ => unable_to_use(lhs, rhs),
         }
     }
 }
 
+pub struct StringOrArithmetic<T: IntOperator + RealOperator + StringOperator> {
+    phantom: PhantomData<T>,
+}
+
+impl<T: IntOperator + RealOperator + StringOperator> BinaryOperator for StringOrArithmetic<T> {
+    fn eval(lhs: Value, rhs: Value) -> OperatorResult {
+        match (&lhs, &rhs) {
+            (Value::String(lhs), Value::String(rhs)) => <T as StringOperator>::eval(lhs, rhs),
+            _ => ArithmeticOrComparison::<T>::eval(lhs, rhs),
+        }
+    }
+}
+
 pub struct Bitwise<T: IntOperator> {
     phantom: PhantomData<T>,
 }
 
 impl<T: IntOperator> BinaryOperator for Bitwise<T> {
     fn eval(lhs: Value, rhs: Value) -> OperatorResult {
-        match (lhs, rhs) {
-            (Value::Integer(lhs), Value::Integer(rhs)) => T::eval(lhs, rhs),
+        match (&lhs, &rhs) {
+            (Value::Integer(lhs), Value::Integer(rhs)) => T::eval(*lhs, *rhs),
             _ => unable_to_use(lhs, rhs),
         }
     }
 }
 
-pub struct Equality<T: BoolOperator + IntOperator + RealOperator> {
+pub struct Equality<T: BoolOperator + IntOperator + RealOperator + StringOperator> {
     phantom: PhantomData<T>,
 }
 
-impl<T: BoolOperator + IntOperator + RealOperator> BinaryOperator for Equality<T> {
+impl<T: BoolOperator + IntOperator + RealOperator + StringOperator> BinaryOperator for Equality<T> {
     fn eval(lhs: Value, rhs: Value) -> OperatorResult {
-        match (lhs, rhs) {
-            (Value::Bool(lhs), Value::Bool(rhs)) => <T as BoolOperator>::eval(lhs, rhs),
-            (Value::Integer(lhs), Value::Integer(rhs)) => <T as IntOperator>::eval(lhs, rhs),
-            (Value::Integer(lhs), Value::Real(rhs)) => <T as RealOperator>::eval(lhs as f64, rhs),
-            (Value::Real(lhs), Value::Integer(rhs)) => <T as RealOperator>::eval(lhs, rhs as f64),
-            (Value::Real(lhs), Value::Real(rhs)) => <T as RealOperator>::eval(lhs, rhs),
+        match (&lhs, &rhs) {
+            (Value::String(lhs), Value::String(rhs)) => <T as StringOperator>::eval(lhs, rhs),
+            (Value::Bool(lhs), Value::Bool(rhs)) => <T as BoolOperator>::eval(*lhs, *rhs),
+            (Value::Integer(lhs), Value::Integer(rhs)) => <T as IntOperator>::eval(*lhs, *rhs),
+            (Value::Integer(lhs), Value::Real(rhs)) => <T as RealOperator>::eval(*lhs as f64, *rhs),
+            (Value::Real(lhs), Value::Integer(rhs)) => <T as RealOperator>::eval(*lhs, *rhs as f64),
+            (Value::Real(lhs), Value::Real(rhs)) => <T as RealOperator>::eval(*lhs, *rhs),
             _ => unable_to_use(lhs, rhs),
         }
     }
 }
+
+pub struct Negate;
+
+impl UnaryOperator for Negate {
+    fn eval(value: Value) -> OperatorResult {
+        match value {
+            Value::Integer(value) => Ok(Value::Integer(value.wrapping_neg())),
+            Value::Real(value) => Ok(Value::Real(-value)),
+            _ => Err((
+                ErrorKind::Type,
+                format!("Can't negate {}, expected number.", value.type_name()),
+            )),
+        }
+    }
+}
+
+pub struct Not;
+
+impl UnaryOperator for Not {
+    fn eval(value: Value) -> OperatorResult {
+        match value {
+            Value::Bool(value) => Ok(Value::Bool(!value)),
+            _ => Err((
+                ErrorKind::Type,
+                format!(
+                    "Can't use {} in logical not operation, expected bool.",
+                    value.type_name()
+                ),
+            )),
+        }
+    }
+}
+
+pub struct BitNot;
+
+impl UnaryOperator for BitNot {
+    fn eval(value: Value) -> OperatorResult {
+        match value {
+            Value::Integer(value) => Ok(Value::Integer(!value)),
+            _ => Err((
+                ErrorKind::Type,
+                format!(
+                    "Can't use {} in bitwise not operation, expected integer.",
+                    value.type_name()
+                ),
+            )),
+        }
+    }
+}
+
+fn array_index(index: &Value, len: usize) -> Result<usize, OperatorError> {
+    match *index {
+        Value::Integer(index) if index >= 0 && (index as u64) < len as u64 => Ok(index as usize),
+        Value::Integer(index) => Err((
+            ErrorKind::Index,
+            format!(
+                "Index {} is out of bounds for array of length {}.",
+                index, len
+            ),
+        )),
+        _ => Err((
+            ErrorKind::Type,
+            format!("Expected integer index, but got {}.", index.type_name()),
+        )),
+    }
+}
+
+// Arrays and maps are compared by identity, so an equal looking key would
+// never find them, and they can change while being keys. Only values
+// compared by content are allowed as keys.
+pub fn check_key(key: &Value) -> Result<(), OperatorError> {
+    match key {
+        Value::Array(_) | Value::Map(_) => Err((
+            ErrorKind::Type,
+            format!("Can't use {} as map key.", key.type_name()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn get_index(container: &Value, index: &Value) -> OperatorResult {
+    match container {
+        Value::Array(array) => {
+            let array = array.borrow();
+            Ok(array[array_index(index, array.len())?].clone())
+        }
+        Value::Map(map) => {
+            check_key(index)?;
+            match map.borrow().get(index) {
+                Some(value) => Ok(value.clone()),
+                None => Err((
+                    ErrorKind::Index,
+                    format!("Key {} is not found in map.", Element(index)),
+                )),
+            }
+        }
+        _ => Err((
+            ErrorKind::Type,
+            format!("Can't index {}.", container.type_name()),
+        )),
+    }
+}
+
+pub fn set_index(container: &Value, index: &Value, value: Value) -> Result<(), OperatorError> {
+    match container {
+        Value::Array(array) => {
+            let mut array = array.borrow_mut();
+            let index = array_index(index, array.len())?;
+            array[index] = value;
+            Ok(())
+        }
+        Value::Map(map) => {
+            check_key(index)?;
+            map.borrow_mut().insert(index.clone(), value);
+            Ok(())
+        }
+        _ => Err((
+            ErrorKind::Type,
+            format!("Can't assign by index to {}.", container.type_name()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_division_overflow_wraps() {
+        assert!(matches!(
+            <Divide as IntOperator>::eval(i64::MIN, -1),
+            Ok(Value::Integer(i64::MIN))
+        ));
+        assert!(matches!(
+            <Modulo as IntOperator>::eval(i64::MIN, -1),
+            Ok(Value::Integer(0))
+        ));
+    }
+
+    #[test]
+    fn integer_division_by_zero_is_arithmetic_error() {
+        for result in [
+            <Divide as IntOperator>::eval(1, 0),
+            <Modulo as IntOperator>::eval(1, 0),
+        ] {
+            assert!(matches!(result, Err((ErrorKind::Arithmetic, _))));
+        }
+    }
+
+    fn array(values: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RefCell::new(values)))
+    }
+
+    #[test]
+    fn displays_array_containing_itself() {
+        let value = array(vec![Value::Integer(0)]);
+        set_index(&value, &Value::Integer(0), value.clone())
+            .ok()
+            .unwrap();
+        assert_eq!(value.to_string(), "[[...]]");
+
+        let outer = array(vec![value.clone(), value]);
+        assert_eq!(outer.to_string(), "[[[...]], [[...]]]");
+    }
+
+    #[test]
+    fn type_errors_name_types() {
+        let value = array(vec![Value::String("a".into())]);
+        match ArithmeticOrComparison::<Subtract>::eval(value, Value::Integer(1)) {
+            Err((ErrorKind::Type, message)) => {
+                assert_eq!(
+                    message,
+                    "Can't use array and integer in this binary operation."
+                )
+            }
+            _ => panic!("expected type error"),
+        }
+    }
+
+    #[test]
+    fn arrays_and_maps_cant_be_keys() {
+        let map = Value::Map(Rc::new(RefCell::new(HashMap::new())));
+        let key = array(vec![Value::Integer(1)]);
+        assert!(matches!(
+            set_index(&map, &key, Value::Integer(2)),
+            Err((ErrorKind::Type, _))
+        ));
+        assert!(matches!(get_index(&map, &key), Err((ErrorKind::Type, _))));
+        assert!(matches!(
+            get_index(&map, &Value::Integer(1)),
+            Err((ErrorKind::Index, _))
+        ));
+    }
+}
@@ -0,0 +1,505 @@
+use std::fmt::{Display, Formatter};
+
+use crate::{
+    function::Function,
+    module::Module,
+    program::{Opcode, Program},
+};
+
+// Largest frame a function may have, frames are allocated whole on every
+// call, so a bigger one in a loaded file could exhaust memory at once.
+pub const MAX_FRAME_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    LocationsMismatch {
+        opcodes: usize,
+        locations: usize,
+    },
+    ParamsExceedFrame {
+        params_count: usize,
+        frame_size: usize,
+    },
+    FrameTooLarge(usize),
+    ConstantOutOfRange(usize),
+    LocalOutOfRange(usize),
+    FunctionOutOfRange(usize),
+    JumpOutOfRange(usize),
+    StackUnderflow {
+        needed: usize,
+        depth: usize,
+    },
+    StackMismatch {
+        expected: usize,
+        found: usize,
+    },
+    MissingReturn,
+    EntryResult {
+        depth: usize,
+    },
+}
+
+impl Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyErrorKind::LocationsMismatch { opcodes, locations } => write!(
+                f,
+                "{} locations are given for {} opcodes.",
+                locations, opcodes
+            ),
+            VerifyErrorKind::ParamsExceedFrame {
+                params_count,
+                frame_size,
+            } => write!(
+                f,
+                "{} params don't fit in frame of size {}.",
+                params_count, frame_size
+            ),
+            VerifyErrorKind::FrameTooLarge(frame_size) => write!(
+                f,
+                "Frame size {} exceeds the maximum of {}.",
+                frame_size, MAX_FRAME_SIZE
+            ),
+            VerifyErrorKind::ConstantOutOfRange(index) => {
+                write!(f, "Constant {} doesn't exist.", index)
+            }
+            VerifyErrorKind::LocalOutOfRange(index) => {
+                write!(f, "Local {} is outside of the frame.", index)
+            }
+            VerifyErrorKind::FunctionOutOfRange(index) => {
+                write!(f, "Function {} doesn't exist.", index)
+            }
+            VerifyErrorKind::JumpOutOfRange(position) => {
+                write!(f, "Jump target {} is past the end of function.", position)
+            }
+            VerifyErrorKind::StackUnderflow { needed, depth } => write!(
+                f,
+                "Opcode needs {} values, but the stack has {}.",
+                needed, depth
+            ),
+            VerifyErrorKind::StackMismatch { expected, found } => write!(
+                f,
+                "Stack depth is {} on one path and {} on another.",
+                expected, found
+            ),
+            VerifyErrorKind::MissingReturn => write!(f, "Function ends without return."),
+            VerifyErrorKind::EntryResult { depth } => write!(
+                f,
+                "Entry point must end with one value on the stack, but has {}.",
+                depth
+            ),
+        }
+    }
+}
+
+// Problem found in the opcode at "opcode_index", which equals the opcodes
+// count for problems at the end of function or with the function itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub module_index: usize,
+    pub function_index: usize,
+    pub opcode_index: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid bytecode in module {}, fn {}, opcode {}: {}",
+            self.module_index, self.function_index, self.opcode_index, self.kind
+        )
+    }
+}
+
+// Count of values the opcode pops and pushes.
+fn stack_effect(opcode: Opcode, module: &Module) -> (usize, usize) {
+    match opcode {
+        Opcode::Constant(_) | Opcode::LoadLocal(_) => (0, 1),
+        Opcode::Addict
+        | Opcode::Subtract
+        | Opcode::Multiply
+        | Opcode::Divide
+        | Opcode::Modulo
+        | Opcode::And
+        | Opcode::Or
+        | Opcode::Xor
+        | Opcode::Shl
+        | Opcode::Shr
+        | Opcode::Equal
+        | Opcode::NotEqual
+        | Opcode::Greater
+        | Opcode::Less
+        | Opcode::GreaterEqual
+        | Opcode::LessEqual
+        | Opcode::GetIndex => (2, 1),
+        Opcode::Negate | Opcode::Not | Opcode::BitNot => (1, 1),
+        Opcode::JumpFalse(_) | Opcode::JumpTrue(_) => (1, 0),
+        Opcode::Jump(_) => (0, 0),
+        Opcode::StoreLocal(_) | Opcode::Drop => (1, 0),
+        Opcode::Push => (1, 2),
+        Opcode::Call(index) => (module.functions[index].params_count, 1),
+        Opcode::Return => (1, 0),
+        Opcode::MakeArray(count) => (count, 1),
+        Opcode::MakeMap(count) => (count.saturating_mul(2), 1),
+        Opcode::SetIndex => (3, 1),
+    }
+}
+
+// Positions the execution continues at after the opcode, "None" stands for
+// leaving the function.
+fn successors(opcode: Opcode, index: usize) -> [Option<usize>; 2] {
+    match opcode {
+        Opcode::Jump(position) => [Some(position), None],
+        Opcode::JumpFalse(position) | Opcode::JumpTrue(position) => {
+            [Some(index + 1), Some(position)]
+        }
+        Opcode::Return => [None, None],
+        _ => [Some(index + 1), None],
+    }
+}
+
+fn check_operand(
+    opcode: Opcode,
+    module: &Module,
+    function: &Function,
+) -> Result<(), VerifyErrorKind> {
+    match opcode {
+        Opcode::Constant(index) if index >= module.constants.len() => {
+            Err(VerifyErrorKind::ConstantOutOfRange(index))
+        }
+        Opcode::LoadLocal(index) | Opcode::StoreLocal(index) if index >= function.frame_size => {
+            Err(VerifyErrorKind::LocalOutOfRange(index))
+        }
+        Opcode::Call(index) if index >= module.functions.len() => {
+            Err(VerifyErrorKind::FunctionOutOfRange(index))
+        }
+        Opcode::Jump(position) | Opcode::JumpFalse(position) | Opcode::JumpTrue(position)
+            if position > function.opcodes.len() =>
+        {
+            Err(VerifyErrorKind::JumpOutOfRange(position))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Follows every path through the function, tracking the stack depth each
+// opcode starts with. Paths meeting at an opcode must agree on the depth,
+// so every opcode has a single known depth.
+fn check_stack(
+    module: &Module,
+    function: &Function,
+    is_entry: bool,
+) -> Result<(), (usize, VerifyErrorKind)> {
+    let count = function.opcodes.len();
+    let mut depths: Vec<Option<usize>> = vec![None; count + 1];
+    let mut pending = vec![(0, 0)];
+
+    while let Some((index, depth)) = pending.pop() {
+        match depths[index] {
+            Some(expected) if expected != depth => {
+                return Err((
+                    index,
+                    VerifyErrorKind::StackMismatch {
+                        expected,
+                        found: depth,
+                    },
+                ))
+            }
+            Some(_) => continue,
+            None => depths[index] = Some(depth),
+        }
+
+        if index == count {
+            if !is_entry {
+                return Err((index, VerifyErrorKind::MissingReturn));
+            }
+            if depth != 1 {
+                return Err((index, VerifyErrorKind::EntryResult { depth }));
+            }
+            continue;
+        }
+
+        let opcode = function.opcodes[index];
+        let (pops, pushes) = stack_effect(opcode, module);
+        if depth < pops {
+            return Err((
+                index,
+                VerifyErrorKind::StackUnderflow {
+                    needed: pops,
+                    depth,
+                },
+            ));
+        }
+        for next in successors(opcode, index).into_iter().flatten() {
+            pending.push((next, depth - pops + pushes));
+        }
+    }
+    Ok(())
+}
+
+fn verify_function(
+    module: &Module,
+    function: &Function,
+    is_entry: bool,
+) -> Result<(), (usize, VerifyErrorKind)> {
+    let count = function.opcodes.len();
+    if function.locations.len() != count {
+        return Err((
+            count,
+            VerifyErrorKind::LocationsMismatch {
+                opcodes: count,
+                locations: function.locations.len(),
+            },
+        ));
+    }
+    if function.frame_size > MAX_FRAME_SIZE {
+        return Err((count, VerifyErrorKind::FrameTooLarge(function.frame_size)));
+    }
+    if function.params_count > function.frame_size {
+        return Err((
+            count,
+            VerifyErrorKind::ParamsExceedFrame {
+                params_count: function.params_count,
+                frame_size: function.frame_size,
+            },
+        ));
+    }
+    for (index, &opcode) in function.opcodes.iter().enumerate() {
+        check_operand(opcode, module, function).map_err(|kind| (index, kind))?;
+    }
+    check_stack(module, function, is_entry)
+}
+
+// Checks that running the program can't index out of constants, locals,
+// functions or code and never pops from an empty stack, so bytecode loaded
+// from files can't crash the interpreter. Function 0 of every module is its
+// entry point.
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    for (module_index, module) in program.modules().iter().enumerate() {
+        if module.functions.is_empty() {
+            return Err(VerifyError {
+                module_index,
+                function_index: 0,
+                opcode_index: 0,
+                kind: VerifyErrorKind::FunctionOutOfRange(0),
+            });
+        }
+        for (function_index, function) in module.functions.iter().enumerate() {
+            verify_function(module, function, function_index == 0).map_err(
+                |(opcode_index, kind)| VerifyError {
+                    module_index,
+                    function_index,
+                    opcode_index,
+                    kind,
+                },
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    fn function(opcodes: &[Opcode], frame_size: usize, params_count: usize) -> Function {
+        Function {
+            opcodes: opcodes.into(),
+            locations: vec![0..0; opcodes.len()].into(),
+            frame_size,
+            params_count,
+        }
+    }
+
+    // Entry point returning a constant, so the function under test can be
+    // placed at index 1.
+    fn entry() -> Function {
+        function(&[Opcode::Constant(0)], 0, 0)
+    }
+
+    fn program(functions: Vec<Function>) -> Program {
+        let mut program = Program::new();
+        program.push(Module {
+            functions: functions.into(),
+            constants: Box::new([Value::Integer(1)]),
+        });
+        program
+    }
+
+    fn error(functions: Vec<Function>) -> VerifyError {
+        verify(&program(functions)).expect_err("program must be rejected")
+    }
+
+    fn kind(function: Function) -> VerifyErrorKind {
+        error(vec![entry(), function]).kind
+    }
+
+    #[test]
+    fn rejects_locations_mismatch() {
+        let mut function = function(&[Opcode::Constant(0), Opcode::Return], 0, 0);
+        function.locations = Box::new([]);
+        assert_eq!(
+            kind(function),
+            VerifyErrorKind::LocationsMismatch {
+                opcodes: 2,
+                locations: 0
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_params_exceeding_frame() {
+        assert_eq!(
+            kind(function(&[Opcode::Constant(0), Opcode::Return], 1, 2)),
+            VerifyErrorKind::ParamsExceedFrame {
+                params_count: 2,
+                frame_size: 1
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_too_large_frame() {
+        let frame_size = MAX_FRAME_SIZE + 1;
+        assert_eq!(
+            kind(function(
+                &[Opcode::Constant(0), Opcode::Return],
+                frame_size,
+                0
+            )),
+            VerifyErrorKind::FrameTooLarge(frame_size)
+        );
+        let error = error(vec![function(&[Opcode::Constant(0)], 1 << 60, 0)]);
+        assert_eq!(error.kind, VerifyErrorKind::FrameTooLarge(1 << 60));
+    }
+
+    #[test]
+    fn rejects_missing_constant() {
+        assert_eq!(
+            kind(function(&[Opcode::Constant(1), Opcode::Return], 0, 0)),
+            VerifyErrorKind::ConstantOutOfRange(1)
+        );
+    }
+
+    #[test]
+    fn rejects_local_outside_frame() {
+        assert_eq!(
+            kind(function(&[Opcode::LoadLocal(1), Opcode::Return], 1, 0)),
+            VerifyErrorKind::LocalOutOfRange(1)
+        );
+        assert_eq!(
+            kind(function(
+                &[Opcode::Constant(0), Opcode::StoreLocal(0), Opcode::Return],
+                0,
+                0
+            )),
+            VerifyErrorKind::LocalOutOfRange(0)
+        );
+    }
+
+    #[test]
+    fn rejects_missing_function() {
+        assert_eq!(
+            kind(function(&[Opcode::Call(2), Opcode::Return], 0, 0)),
+            VerifyErrorKind::FunctionOutOfRange(2)
+        );
+        let error = error(Vec::new());
+        assert_eq!(error.kind, VerifyErrorKind::FunctionOutOfRange(0));
+    }
+
+    #[test]
+    fn rejects_jump_past_end() {
+        assert_eq!(
+            kind(function(
+                &[Opcode::Jump(4), Opcode::Constant(0), Opcode::Return],
+                0,
+                0
+            )),
+            VerifyErrorKind::JumpOutOfRange(4)
+        );
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        let error = error(vec![
+            entry(),
+            function(&[Opcode::Constant(0), Opcode::Addict, Opcode::Return], 0, 0),
+        ]);
+        assert_eq!(error.function_index, 1);
+        assert_eq!(error.opcode_index, 1);
+        assert_eq!(
+            error.kind,
+            VerifyErrorKind::StackUnderflow {
+                needed: 2,
+                depth: 1
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_stack_mismatch() {
+        // One path pushes an extra value before joining the other.
+        assert_eq!(
+            kind(function(
+                &[
+                    Opcode::Constant(0),
+                    Opcode::JumpFalse(3),
+                    Opcode::Constant(0),
+                    Opcode::Constant(0),
+                    Opcode::Return,
+                ],
+                0,
+                0
+            )),
+            VerifyErrorKind::StackMismatch {
+                expected: 0,
+                found: 1
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_missing_return() {
+        assert_eq!(
+            kind(function(&[Opcode::Constant(0)], 0, 0)),
+            VerifyErrorKind::MissingReturn
+        );
+    }
+
+    #[test]
+    fn rejects_entry_without_single_result() {
+        let error = error(vec![function(
+            &[Opcode::Constant(0), Opcode::Constant(0)],
+            0,
+            0,
+        )]);
+        assert_eq!(error.kind, VerifyErrorKind::EntryResult { depth: 2 });
+    }
+
+    #[test]
+    fn accepts_compiled_scripts() {
+        let scripts = [
+            "1 + 2",
+            "fn g(x) { if x > 2 { return x * 10 } x + [1, 2, loop { break 3 }][2] }
+             g(1) + g(5)",
+            "fn h(n) { let s = 0; while true { n -= 1; if n < 0 { break } \
+             if n % 2 == 0 { continue } s += n }; s }
+             h(10)",
+            "let i = 0;
+             let v = loop { i += 1; if i > 5 { break [i, {a: i}] } \
+             else { 1 + (if i > 100 { break 0 } else { 2 }) } };
+             let m = {};
+             m[\"k\"] = 1 + loop { break 41 };
+             [v, m, 1 + loop { if true { break 2 } }]",
+            "fn f() { g() } fn g() { if true { return 1 } 2 } f()",
+            "let x = 1; { let y = x; x = y + 1; }",
+        ];
+        for script in scripts {
+            if let Err(error) = verify(&crate::testing::build(script)) {
+                panic!("{}\n{}", script, error);
+            }
+        }
+    }
+}